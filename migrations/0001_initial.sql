@@ -0,0 +1,39 @@
+-- The schema as it stood when migrations were introduced. Every statement is
+-- guarded so the same file also completes databases created before then.
+
+CREATE TABLE IF NOT EXISTS game_codes (
+    code TEXT PRIMARY KEY,
+    game_uuid TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS games (
+    uuid TEXT PRIMARY KEY,
+    state TEXT NOT NULL,
+    version INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE TABLE IF NOT EXISTS users (
+    id TEXT PRIMARY KEY,
+    username TEXT UNIQUE NOT NULL,
+    is_guest INTEGER NOT NULL DEFAULT 0,
+    avatar TEXT
+);
+
+-- Usernames are unique regardless of case.
+CREATE UNIQUE INDEX IF NOT EXISTS users_username_nocase
+    ON users (username COLLATE NOCASE);
+
+CREATE TABLE IF NOT EXISTS user_credentials (
+    user_id TEXT PRIMARY KEY REFERENCES users(id),
+    password_hash TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+    token_hash TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL REFERENCES users(id),
+    family_id TEXT NOT NULL,
+    expires_at INTEGER NOT NULL,
+    revoked INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE INDEX IF NOT EXISTS refresh_tokens_family ON refresh_tokens (family_id);
@@ -0,0 +1,180 @@
+-- Moves games out of the JSON blob in games.state and into tables of their
+-- own, so players, prompts and guesses can be queried across games.
+
+-- Deleted accounts are kept as tombstones so the games they played still
+-- refer to them.
+ALTER TABLE users ADD COLUMN deleted_at INTEGER;
+
+ALTER TABLE games ADD COLUMN status TEXT NOT NULL DEFAULT 'waiting';
+ALTER TABLE games ADD COLUMN current_round INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE games ADD COLUMN total_rounds INTEGER NOT NULL DEFAULT 3;
+ALTER TABLE games ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE game_players (
+    game_uuid TEXT NOT NULL REFERENCES games(uuid) ON DELETE CASCADE,
+    user_id TEXT NOT NULL REFERENCES users(id),
+    -- Join order, which is the order players are listed in.
+    seat INTEGER NOT NULL,
+    score INTEGER NOT NULL DEFAULT 0,
+    ready INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (game_uuid, user_id)
+);
+
+CREATE INDEX game_players_user ON game_players (user_id);
+
+-- Every prompt submitted in a round, whether or not it was drawn.
+CREATE TABLE prompts (
+    id INTEGER PRIMARY KEY,
+    game_uuid TEXT NOT NULL REFERENCES games(uuid) ON DELETE CASCADE,
+    round INTEGER NOT NULL,
+    author_id TEXT NOT NULL REFERENCES users(id),
+    text TEXT NOT NULL,
+    UNIQUE (game_uuid, round, author_id)
+);
+
+CREATE INDEX prompts_author ON prompts (author_id);
+
+CREATE TABLE images (
+    prompt_id INTEGER PRIMARY KEY REFERENCES prompts(id) ON DELETE CASCADE,
+    url TEXT NOT NULL
+);
+
+-- The prompt drawn in each round. A round is over once finished_at is set.
+CREATE TABLE rounds (
+    game_uuid TEXT NOT NULL REFERENCES games(uuid) ON DELETE CASCADE,
+    round INTEGER NOT NULL,
+    prompt_id INTEGER NOT NULL REFERENCES prompts(id),
+    finished_at INTEGER,
+    PRIMARY KEY (game_uuid, round)
+);
+
+CREATE INDEX rounds_prompt ON rounds (prompt_id);
+
+CREATE TABLE guesses (
+    game_uuid TEXT NOT NULL,
+    round INTEGER NOT NULL,
+    player_id TEXT NOT NULL REFERENCES users(id),
+    guess TEXT NOT NULL,
+    score INTEGER NOT NULL,
+    submitted_at INTEGER NOT NULL,
+    PRIMARY KEY (game_uuid, round, player_id),
+    FOREIGN KEY (game_uuid, round) REFERENCES rounds(game_uuid, round) ON DELETE CASCADE
+);
+
+CREATE INDEX guesses_player ON guesses (player_id);
+
+-- Convert the existing blobs. Games whose state isn't valid JSON keep the
+-- column defaults and no players.
+
+UPDATE games SET
+    status = json_extract(state, '$.status'),
+    current_round = json_extract(state, '$.current_round'),
+    total_rounds = json_extract(state, '$.total_rounds'),
+    created_at = CAST(strftime('%s', 'now') AS INTEGER)
+WHERE json_valid(state);
+
+-- Players whose accounts were deleted before tombstones existed.
+INSERT OR IGNORE INTO users (id, username, deleted_at)
+SELECT DISTINCT
+    json_extract(player.value, '$.id'),
+    json_extract(player.value, '$.id'),
+    CAST(strftime('%s', 'now') AS INTEGER)
+FROM games, json_each(games.state, '$.players') AS player
+WHERE json_valid(games.state) AND json_extract(player.value, '$.id') != '';
+
+INSERT OR IGNORE INTO game_players (game_uuid, user_id, seat, score, ready)
+SELECT
+    games.uuid,
+    json_extract(player.value, '$.id'),
+    player.key,
+    json_extract(player.value, '$.score'),
+    json_extract(player.value, '$.ready')
+FROM games, json_each(games.state, '$.players') AS player
+WHERE json_valid(games.state)
+    AND json_extract(player.value, '$.id') IN (SELECT id FROM users);
+
+-- Finished rounds.
+INSERT OR IGNORE INTO prompts (game_uuid, round, author_id, text)
+SELECT
+    games.uuid,
+    json_extract(result.value, '$.round'),
+    json_extract(result.value, '$.author_id'),
+    json_extract(result.value, '$.prompt')
+FROM games, json_each(games.state, '$.round_results') AS result
+WHERE json_valid(games.state)
+    AND json_extract(result.value, '$.author_id') IN (SELECT id FROM users);
+
+INSERT OR IGNORE INTO rounds (game_uuid, round, prompt_id, finished_at)
+SELECT games.uuid, prompts.round, prompts.id, CAST(strftime('%s', 'now') AS INTEGER)
+FROM games, json_each(games.state, '$.round_results') AS result
+JOIN prompts ON prompts.game_uuid = games.uuid
+    AND prompts.round = json_extract(result.value, '$.round')
+    AND prompts.author_id = json_extract(result.value, '$.author_id')
+WHERE json_valid(games.state);
+
+INSERT OR IGNORE INTO images (prompt_id, url)
+SELECT prompts.id, json_extract(result.value, '$.image')
+FROM games, json_each(games.state, '$.round_results') AS result
+JOIN prompts ON prompts.game_uuid = games.uuid
+    AND prompts.round = json_extract(result.value, '$.round')
+    AND prompts.author_id = json_extract(result.value, '$.author_id')
+WHERE json_valid(games.state) AND json_extract(result.value, '$.image') != '';
+
+INSERT OR IGNORE INTO guesses (game_uuid, round, player_id, guess, score, submitted_at)
+SELECT
+    games.uuid,
+    rounds.round,
+    json_extract(guess.value, '$.player_id'),
+    json_extract(guess.value, '$.guess'),
+    json_extract(guess.value, '$.score'),
+    CAST(strftime('%s', 'now') AS INTEGER)
+FROM games, json_each(games.state, '$.round_results') AS result,
+    json_each(result.value, '$.guesses') AS guess
+JOIN rounds ON rounds.game_uuid = games.uuid
+    AND rounds.round = json_extract(result.value, '$.round')
+WHERE json_valid(games.state)
+    AND json_extract(guess.value, '$.player_id') IN (SELECT id FROM users);
+
+-- The round in progress, for games that aren't finished.
+INSERT OR IGNORE INTO prompts (game_uuid, round, author_id, text)
+SELECT
+    games.uuid,
+    games.current_round,
+    json_extract(submitted.value, '$[0]'),
+    json_extract(submitted.value, '$[1]')
+FROM games, json_each(games.state, '$.submitted_prompts') AS submitted
+WHERE json_valid(games.state) AND games.status != 'finished'
+    AND json_extract(submitted.value, '$[0]') IN (SELECT id FROM users);
+
+INSERT OR IGNORE INTO rounds (game_uuid, round, prompt_id)
+SELECT games.uuid, games.current_round, prompts.id
+FROM games
+JOIN prompts ON prompts.game_uuid = games.uuid
+    AND prompts.round = games.current_round
+    AND prompts.author_id = json_extract(games.state, '$.current_prompt_author')
+WHERE json_valid(games.state) AND games.status IN ('generating', 'guessing');
+
+INSERT OR IGNORE INTO images (prompt_id, url)
+SELECT rounds.prompt_id, json_extract(games.state, '$.current_image')
+FROM games
+JOIN rounds ON rounds.game_uuid = games.uuid
+    AND rounds.round = games.current_round
+    AND rounds.finished_at IS NULL
+WHERE json_valid(games.state) AND json_extract(games.state, '$.current_image') != '';
+
+INSERT OR IGNORE INTO guesses (game_uuid, round, player_id, guess, score, submitted_at)
+SELECT
+    games.uuid,
+    rounds.round,
+    json_extract(submitted.value, '$[0]'),
+    json_extract(submitted.value, '$[1]'),
+    CAST(json_extract(submitted.value, '$[2]') AS INTEGER),
+    CAST(strftime('%s', 'now') AS INTEGER)
+FROM games, json_each(games.state, '$.submitted_guesses') AS submitted
+JOIN rounds ON rounds.game_uuid = games.uuid
+    AND rounds.round = games.current_round
+    AND rounds.finished_at IS NULL
+WHERE json_valid(games.state)
+    AND json_extract(submitted.value, '$[0]') IN (SELECT id FROM users);
+
+ALTER TABLE games DROP COLUMN state;
@@ -0,0 +1,20 @@
+-- Adds a host to each game, per-game player limits and the players a host
+-- has kicked.
+
+ALTER TABLE games ADD COLUMN host_id TEXT REFERENCES users(id);
+ALTER TABLE games ADD COLUMN min_players INTEGER NOT NULL DEFAULT 2;
+ALTER TABLE games ADD COLUMN max_players INTEGER NOT NULL DEFAULT 8;
+
+-- Existing games are hosted by whoever joined first.
+UPDATE games SET host_id = (
+    SELECT user_id FROM game_players
+    WHERE game_players.game_uuid = games.uuid
+    ORDER BY seat
+    LIMIT 1
+);
+
+CREATE TABLE game_kicks (
+    game_uuid TEXT NOT NULL REFERENCES games(uuid) ON DELETE CASCADE,
+    user_id TEXT NOT NULL REFERENCES users(id),
+    PRIMARY KEY (game_uuid, user_id)
+);
@@ -0,0 +1,12 @@
+-- The rules each game was created with. Games from before settings existed
+-- get the defaults they were effectively played with.
+
+ALTER TABLE games ADD COLUMN imagining_seconds INTEGER NOT NULL DEFAULT 90;
+ALTER TABLE games ADD COLUMN guessing_seconds INTEGER NOT NULL DEFAULT 60;
+ALTER TABLE games ADD COLUMN image_model TEXT NOT NULL DEFAULT 'dall-e-3';
+ALTER TABLE games ADD COLUMN image_size TEXT NOT NULL DEFAULT '1024x1024';
+ALTER TABLE games ADD COLUMN image_quality TEXT NOT NULL DEFAULT 'standard';
+ALTER TABLE games ADD COLUMN scoring TEXT NOT NULL DEFAULT 'similarity';
+ALTER TABLE games ADD COLUMN language TEXT NOT NULL DEFAULT 'en';
+ALTER TABLE games ADD COLUMN theme TEXT;
+ALTER TABLE games ADD COLUMN visibility TEXT NOT NULL DEFAULT 'private';
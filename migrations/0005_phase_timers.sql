@@ -0,0 +1,16 @@
+-- Deadlines for each phase of a game, and the players who let one run out.
+
+ALTER TABLE games ADD COLUMN phase_deadline INTEGER;
+ALTER TABLE game_players ADD COLUMN afk INTEGER NOT NULL DEFAULT 0;
+
+CREATE INDEX games_phase_deadline ON games (phase_deadline)
+    WHERE phase_deadline IS NOT NULL;
+
+-- Games already in progress get a full phase from now, so ones that stalled
+-- before timers existed start moving again.
+UPDATE games SET phase_deadline = CAST(strftime('%s', 'now') AS INTEGER) + CASE status
+    WHEN 'imagining' THEN imagining_seconds
+    WHEN 'generating' THEN 120
+    ELSE guessing_seconds
+END
+WHERE status IN ('imagining', 'generating', 'guessing');
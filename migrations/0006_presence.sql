@@ -0,0 +1,10 @@
+-- Whether each player is connected to their game, so players who drop out
+-- can be told apart from those who are just slow, and removed if they don't
+-- come back.
+
+ALTER TABLE game_players ADD COLUMN connected_at INTEGER;
+ALTER TABLE game_players ADD COLUMN away_at INTEGER;
+ALTER TABLE game_players ADD COLUMN disconnected_at INTEGER;
+
+CREATE INDEX game_players_disconnected_at ON game_players (disconnected_at)
+    WHERE disconnected_at IS NOT NULL;
@@ -0,0 +1,12 @@
+-- Avatars are kept in the database and served by the API, since the image
+-- URLs providers hand out expire.
+
+CREATE TABLE avatars (
+    user_id TEXT PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    image BLOB NOT NULL,
+    content_type TEXT NOT NULL,
+    updated_at INTEGER NOT NULL
+);
+
+-- The provider URLs saved so far have expired or soon will.
+UPDATE users SET avatar = NULL WHERE avatar IS NOT NULL;
@@ -0,0 +1,16 @@
+-- Games saved as `generating` or `guessing` before any prompt was drawn were
+-- normalized without a row in `rounds`, so their round could never be
+-- finished. They go back to collecting prompts, with a full phase from now;
+-- prompts already submitted are kept and drawn from when it runs out.
+
+UPDATE games SET
+    status = 'imagining',
+    phase_deadline = CAST(strftime('%s', 'now') AS INTEGER) + imagining_seconds,
+    version = version + 1
+WHERE status IN ('generating', 'guessing')
+    AND NOT EXISTS (
+        SELECT 1 FROM rounds
+        WHERE rounds.game_uuid = games.uuid
+            AND rounds.round = games.current_round
+            AND rounds.finished_at IS NULL
+    );
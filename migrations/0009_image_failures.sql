@@ -0,0 +1,4 @@
+-- How many images in a row a game has failed to get, so a game whose images
+-- never work can be ended instead of retried forever.
+
+ALTER TABLE games ADD COLUMN image_failures INTEGER NOT NULL DEFAULT 0;
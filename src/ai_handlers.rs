@@ -1,316 +1,217 @@
 // ai_handlers.rs
+use crate::config::Config;
+use crate::error::AppError;
+use crate::providers::{AiProvider, ProviderRegistry};
 use actix_multipart::Multipart;
-use actix_web::{web, HttpResponse, Responder};
-use futures::{StreamExt, TryStreamExt};
+use actix_web::web::Bytes;
+use actix_web::{web, HttpResponse};
+use futures::{future, StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
-use std::env;
+use serde_json::json;
 
 #[derive(Serialize, Deserialize, Debug)]
-struct Message {
-    role: String,
-    content: String,
+pub(crate) struct Message {
+    pub(crate) role: String,
+    pub(crate) content: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RequestPayload {
-    model: String,
-    messages: Vec<Message>,
+    pub(crate) model: String,
+    pub(crate) messages: Vec<Message>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) max_tokens: Option<u32>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TextToSpeechRequestPayload {
-    model: String,
-    input: String,
-    voice: String,
-}
-
-#[derive(Deserialize, Debug)]
-struct Choice {
-    message: Message,
-    finish_reason: String,
-    index: i32,
-}
-
-#[derive(Deserialize, Debug)]
-struct Usage {
-    prompt_tokens: i32,
-    completion_tokens: i32,
-    total_tokens: i32,
-}
-
-#[derive(Deserialize, Debug)]
-struct Response {
-    id: String,
-    object: String,
-    created: i64,
-    model: String,
-    choices: Vec<Choice>,
-    usage: Usage,
-}
-
-#[derive(Deserialize, Debug)]
-struct AnthropicResponse {
-    completion: String,
-    stop_reason: String,
-    truncated: bool,
-    log_id: String,
+    pub(crate) model: String,
+    pub(crate) input: String,
+    pub(crate) voice: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ImageRequestPayload {
-    model: String,
-    prompt: String,
-    size: String,
-    quality: String,
-    n: i32,
+    pub(crate) model: String,
+    pub(crate) prompt: String,
+    pub(crate) size: String,
+    pub(crate) quality: String,
+    pub(crate) n: i32,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-struct SpeechToTextRequestPayload {
-    model: String,
-    file: String,
-}
-
-#[derive(Deserialize, Debug)]
-struct ImageData {
-    url: String,
-}
-
-#[derive(Deserialize, Debug)]
-struct ImageResponse {
-    data: Vec<ImageData>,
+pub(crate) struct SpeechToTextRequestPayload {
+    pub(crate) model: String,
+    pub(crate) file: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct EmbeddingRequestPayload {
-    model: String,
-    input: Vec<String>,
+    pub(crate) model: String,
+    pub(crate) input: Vec<String>,
 }
 
-async fn send_request(payload: &RequestPayload) -> Result<String, reqwest::Error> {
-    let api_key = if payload.model.starts_with("claude") {
-        env::var("ANTHROPIC_API_KEY").expect("ANTHROPIC_API_KEY must be set")
-    } else {
-        env::var("OPENAI_API_KEY").expect("OPENAI_API_KEY must be set")
-    };
-
-    let url = if payload.model.starts_with("claude") {
-        "https://api.anthropic.com/v1/complete"
-    } else {
-        "https://api.openai.com/v1/chat/completions"
-    };
+pub(crate) fn provider_for<'a>(
+    registry: &'a ProviderRegistry,
+    model: &str,
+) -> Result<&'a dyn AiProvider, AppError> {
+    registry.for_model(model).ok_or_else(|| {
+        AppError::BadRequest(format!("No AI provider available for model {}", model))
+    })
+}
 
-    let response = reqwest::Client::new()
-        .post(url)
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .json(payload)
-        .send()
-        .await?
-        .text()
+pub async fn generate_chat(
+    registry: web::Data<ProviderRegistry>,
+    payload: web::Json<RequestPayload>,
+) -> Result<HttpResponse, AppError> {
+    let generated_chat = provider_for(&registry, &payload.model)?
+        .chat(&payload)
         .await?;
 
-    Ok(response)
+    Ok(HttpResponse::Ok().body(generated_chat))
 }
 
-pub async fn generate_chat(payload: web::Json<RequestPayload>) -> impl Responder {
-    let response_json = match send_request(&payload).await {
-        Ok(json) => json,
-        Err(e) => {
-            eprintln!("Error: {}", e);
-            return HttpResponse::InternalServerError().finish();
-        }
-    };
-
-    let generated_chat = if payload.model.starts_with("claude") {
-        let response: AnthropicResponse = serde_json::from_str(&response_json).unwrap();
-        response.completion
-    } else {
-        let response: Response = serde_json::from_str(&response_json).unwrap();
-        response.choices[0].message.content.to_string()
-    };
-
-    HttpResponse::Ok().body(generated_chat)
+fn sse_frame(event: &str, data: &serde_json::Value) -> Bytes {
+    Bytes::from(format!("event: {}\ndata: {}\n\n", event, data))
 }
 
-async fn send_speech_to_text_request(
-    payload: &SpeechToTextRequestPayload,
-    file_contents: &[u8],
-) -> Result<String, Box<dyn std::error::Error>> {
-    let api_key = env::var("OPENAI_API_KEY").expect("OPENAI_API_KEY must be set");
-    let url = "https://api.openai.com/v1/audio/transcriptions";
-
-    let part = reqwest::multipart::Part::bytes(file_contents.to_vec())
-        .file_name(payload.file.clone())
-        .mime_str("audio/wav")?;
-
-    let form = reqwest::multipart::Form::new()
-        .text("model", payload.model.clone())
-        .part("file", part);
-
-    let response = reqwest::Client::new()
-        .post(url)
-        .header("Authorization", format!("Bearer {}", api_key))
-        .multipart(form)
-        .send()
-        .await?
-        .text()
+pub async fn generate_chat_stream(
+    registry: web::Data<ProviderRegistry>,
+    payload: web::Json<RequestPayload>,
+) -> Result<HttpResponse, AppError> {
+    let events = provider_for(&registry, &payload.model)?
+        .chat_stream(&payload)
         .await?;
 
-    Ok(response)
+    // Forward each provider event as an SSE frame, ending the stream after
+    // the first error so the client never sees output past a failure.
+    let frames = events.scan(false, |failed, event| {
+        if *failed {
+            return future::ready(None);
+        }
+
+        let frame = match event {
+            Ok(event) => sse_frame(event.name(), &json!(event)),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                *failed = true;
+                sse_frame(
+                    "error",
+                    &json!({ "code": e.code(), "message": e.to_string() }),
+                )
+            }
+        };
+
+        future::ready(Some(Ok::<_, actix_web::Error>(frame)))
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(frames))
 }
 
-pub async fn transcribe_speech(mut payload: Multipart) -> actix_web::Result<HttpResponse> {
+pub async fn transcribe_speech(
+    registry: web::Data<ProviderRegistry>,
+    config: web::Data<Config>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, AppError> {
     let mut file_contents = Vec::new();
-    while let Ok(Some(mut field)) = payload.try_next().await {
+    while let Some(mut field) = payload.try_next().await.map_err(invalid_upload)? {
         while let Some(chunk) = field.next().await {
-            let data = chunk.unwrap();
+            let data = chunk.map_err(invalid_upload)?;
             file_contents.extend_from_slice(&data);
         }
     }
 
     let payload = SpeechToTextRequestPayload {
-        model: "whisper-1".to_string(),
+        model: config.models.transcription.clone(),
         file: "recording.wav".to_string(),
     };
 
-    let transcription = send_speech_to_text_request(&payload, &file_contents)
-        .await
-        .map_err(actix_web::error::ErrorInternalServerError)?;
+    let transcription = provider_for(&registry, &payload.model)?
+        .transcription(&payload, &file_contents)
+        .await?;
 
-    Ok(HttpResponse::Ok().body(transcription))
+    Ok(HttpResponse::Ok().json(json!({ "text": transcription })))
 }
 
-async fn send_text_to_speech_request(
-    payload: &TextToSpeechRequestPayload,
-) -> Result<Vec<u8>, reqwest::Error> {
-    let api_key = env::var("OPENAI_API_KEY").expect("OPENAI_API_KEY must be set");
-    let url = "https://api.openai.com/v1/audio/speech";
-
-    let response = reqwest::Client::new()
-        .post(url)
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .json(payload)
-        .send()
-        .await?
-        .bytes()
-        .await?
-        .to_vec();
-
-    Ok(response)
+fn invalid_upload(e: actix_multipart::MultipartError) -> AppError {
+    AppError::BadRequest(format!("Invalid audio upload: {}", e))
 }
 
-pub async fn generate_speech(payload: web::Json<TextToSpeechRequestPayload>) -> impl Responder {
-    let audio_data = match send_text_to_speech_request(&payload).await {
-        Ok(data) => data,
-        Err(e) => {
-            eprintln!("Error: {}", e);
-            return HttpResponse::InternalServerError().finish();
-        }
-    };
+pub async fn generate_speech(
+    registry: web::Data<ProviderRegistry>,
+    payload: web::Json<TextToSpeechRequestPayload>,
+) -> Result<HttpResponse, AppError> {
+    let audio_data = provider_for(&registry, &payload.model)?
+        .speech(&payload)
+        .await?;
 
-    HttpResponse::Ok()
+    Ok(HttpResponse::Ok()
         .content_type("audio/mpeg")
-        .body(audio_data)
+        .body(audio_data))
 }
 
-async fn send_image_request(payload: &ImageRequestPayload) -> Result<String, reqwest::Error> {
-    let api_key = env::var("OPENAI_API_KEY").expect("OPENAI_API_KEY must be set");
-    let url = "https://api.openai.com/v1/images/generations";
-
-    let response = reqwest::Client::new()
-        .post(url)
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .json(payload)
-        .send()
-        .await?
-        .text()
+pub async fn generate_image(
+    registry: web::Data<ProviderRegistry>,
+    payload: web::Json<ImageRequestPayload>,
+) -> Result<HttpResponse, AppError> {
+    let image_url = provider_for(&registry, &payload.model)?
+        .image(&payload)
         .await?;
 
-    Ok(response)
-}
-
-pub async fn generate_image(payload: web::Json<ImageRequestPayload>) -> impl Responder {
-    let response_json = match send_image_request(&payload).await {
-        Ok(json) => json,
-        Err(e) => {
-            eprintln!("Error: {}", e);
-            return HttpResponse::InternalServerError().finish();
-        }
-    };
-
-    let response: ImageResponse = serde_json::from_str(&response_json).unwrap();
-    let image_url = &response.data[0].url;
-
-    HttpResponse::Ok().body(image_url.to_string())
+    Ok(HttpResponse::Ok().body(image_url))
 }
 
-async fn send_embedding_request(
-    payload: &EmbeddingRequestPayload,
-) -> Result<String, reqwest::Error> {
-    let api_key = env::var("OPENAI_API_KEY").expect("OPENAI_API_KEY must be set");
-    let url = "https://api.openai.com/v1/embeddings";
-
-    let response = reqwest::Client::new()
-        .post(url)
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .json(payload)
-        .send()
-        .await?
-        .text()
+pub async fn get_embeddings(
+    registry: web::Data<ProviderRegistry>,
+    payload: web::Json<EmbeddingRequestPayload>,
+) -> Result<HttpResponse, AppError> {
+    let embeddings = provider_for(&registry, &payload.model)?
+        .embeddings(&payload)
         .await?;
 
-    Ok(response)
+    Ok(HttpResponse::Ok().json(json!({ "embeddings": embeddings })))
 }
 
-pub async fn get_embeddings(payload: web::Json<EmbeddingRequestPayload>) -> impl Responder {
-    let response_json = match send_embedding_request(&payload).await {
-        Ok(json) => json,
-        Err(e) => {
-            eprintln!("Error: {}", e);
-            return HttpResponse::InternalServerError().finish();
-        }
-    };
-
-    HttpResponse::Ok().body(response_json)
-}
-
-pub async fn calculate_similarity(prompt: web::Json<String>, guess: web::Json<String>) -> String {
+/// Scores how close `guess` is to `prompt` from 0 to 100. Embedding models
+/// rate even unrelated texts as somewhat similar, so only cosine similarity
+/// above `baseline` counts, stretched over the whole range.
+pub async fn calculate_similarity(
+    registry: &ProviderRegistry,
+    model: &str,
+    baseline: f64,
+    prompt: &str,
+    guess: &str,
+) -> Result<u32, AppError> {
     let payload = EmbeddingRequestPayload {
-        model: "text-embedding-ada-002".to_string(),
+        model: model.to_string(),
         input: vec![prompt.to_string(), guess.to_string()],
     };
 
-    let response_json = match send_embedding_request(&payload).await {
-        Ok(json) => json,
-        Err(e) => {
-            eprintln!("Error: {}", e);
-            return "Error".to_string();
+    let embeddings = provider_for(registry, &payload.model)?
+        .embeddings(&payload)
+        .await?;
+
+    let (prompt_embedding, guess_embedding) = match embeddings.as_slice() {
+        [prompt_embedding, guess_embedding] => (prompt_embedding, guess_embedding),
+        _ => {
+            return Err(AppError::UpstreamUnavailable(format!(
+                "Expected 2 embeddings, got {}",
+                embeddings.len()
+            )))
         }
     };
 
-    let response: serde_json::Value = serde_json::from_str(&response_json).unwrap();
-    let embeddings = response["data"].as_array().unwrap();
-    let prompt_embedding: Vec<f64> = embeddings[0]["embedding"]
-        .as_array()
-        .unwrap()
-        .iter()
-        .map(|v| v.as_f64().unwrap())
-        .collect();
-    let guess_embedding: Vec<f64> = embeddings[1]["embedding"]
-        .as_array()
-        .unwrap()
-        .iter()
-        .map(|v| v.as_f64().unwrap())
-        .collect();
-
-    let similarity = cosine_similarity(&prompt_embedding, &guess_embedding);
-    let score = (similarity * 50.0 + 50.0).round() as u32;
-
-    score.to_string()
+    let similarity = cosine_similarity(prompt_embedding, guess_embedding);
+
+    Ok(similarity_score(similarity, baseline))
+}
+
+fn similarity_score(similarity: f64, baseline: f64) -> u32 {
+    let stretched = (similarity - baseline) / (1.0 - baseline);
+    (stretched.clamp(0.0, 1.0) * 100.0).round() as u32
 }
 
 fn cosine_similarity(a: &[f64], b: &[f64]) -> f64 {
@@ -319,3 +220,38 @@ fn cosine_similarity(a: &[f64], b: &[f64]) -> f64 {
     let magnitude_b: f64 = b.iter().map(|x| x.powi(2)).sum::<f64>().sqrt();
     dot_product / (magnitude_a * magnitude_b)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn similarity_is_stretched_over_the_range_above_the_baseline() {
+        let cases = [
+            (1.0, 100),
+            (0.94, 80),
+            (0.85, 50),
+            (0.76, 20),
+            (0.7, 0),
+            (0.5, 0),
+            (-1.0, 0),
+        ];
+
+        for (similarity, score) in cases {
+            assert_eq!(similarity_score(similarity, 0.7), score, "{}", similarity);
+        }
+    }
+
+    #[test]
+    fn a_zero_baseline_scores_cosine_similarity_directly() {
+        assert_eq!(similarity_score(0.42, 0.0), 42);
+        assert_eq!(similarity_score(-0.3, 0.0), 0);
+    }
+
+    #[test]
+    fn cosine_similarity_ignores_magnitude() {
+        assert!((cosine_similarity(&[1.0, 2.0], &[2.0, 4.0]) - 1.0).abs() < 1e-9);
+        assert!(cosine_similarity(&[1.0, 0.0], &[0.0, 3.0]).abs() < 1e-9);
+        assert!((cosine_similarity(&[1.0, 0.0], &[-1.0, 0.0]) + 1.0).abs() < 1e-9);
+    }
+}
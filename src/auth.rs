@@ -0,0 +1,513 @@
+// auth.rs
+use crate::config::AuthConfig;
+use crate::db::Database;
+use crate::error::AppError;
+use actix_web::dev::Payload;
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::{web, FromRequest, HttpRequest};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use futures::future::LocalBoxFuture;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rand::RngCore;
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const REFRESH_TOKEN_BYTES: usize = 32;
+// HS256 keys shorter than the hash output weaken the signature.
+pub(crate) const MIN_SECRET_LEN: usize = 32;
+
+#[derive(Serialize, Deserialize)]
+struct Claims {
+    sub: String,
+    iat: u64,
+    exp: u64,
+}
+
+/// Signs and verifies the HS256 tokens handed out to users.
+pub struct JwtKeys {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    validation: Validation,
+    ttl: Duration,
+    refresh_ttl: Duration,
+}
+
+impl JwtKeys {
+    pub fn new(secret: &[u8], ttl: Duration, refresh_ttl: Duration) -> Self {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_required_spec_claims(&["sub", "iat", "exp"]);
+
+        JwtKeys {
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+            validation,
+            ttl,
+            refresh_ttl,
+        }
+    }
+
+    /// Without a configured secret, tokens are signed with a random one and
+    /// stop working when the server restarts.
+    pub fn from_config(config: &AuthConfig) -> Self {
+        let secret = match &config.jwt_secret {
+            Some(secret) => secret.clone().into_bytes(),
+            None => {
+                eprintln!("JWT_SECRET not set; issued tokens will not survive a restart");
+                let mut secret = vec![0; MIN_SECRET_LEN];
+                rand::thread_rng().fill_bytes(&mut secret);
+                secret
+            }
+        };
+
+        Self::new(
+            &secret,
+            Duration::from_secs(config.token_ttl_seconds),
+            Duration::from_secs(config.refresh_token_ttl_seconds),
+        )
+    }
+
+    pub fn issue(&self, user_id: &str) -> Result<String, AppError> {
+        let now = unix_now();
+        let claims = Claims {
+            sub: user_id.to_string(),
+            iat: now.as_secs(),
+            exp: (now + self.ttl).as_secs(),
+        };
+
+        jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, &self.encoding)
+            .map_err(|e| AppError::Internal(format!("Error signing token: {}", e)))
+    }
+
+    /// Returns the user id the token was issued to.
+    pub fn verify(&self, token: &str) -> Result<String, AppError> {
+        jsonwebtoken::decode::<Claims>(token, &self.decoding, &self.validation)
+            .map(|data| data.claims.sub)
+            .map_err(|e| match e.kind() {
+                jsonwebtoken::errors::ErrorKind::ExpiredSignature => {
+                    AppError::Unauthorized("Token has expired".to_string())
+                }
+                _ => AppError::Unauthorized("Invalid token".to_string()),
+            })
+    }
+}
+
+pub(crate) fn unix_now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// Hashes a password with Argon2id. Runs on the blocking pool since hashing
+/// is deliberately slow.
+pub async fn hash_password(password: String) -> Result<String, AppError> {
+    web::block(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+    })
+    .await
+    .map_err(|e| AppError::Internal(format!("Error hashing password: {}", e)))?
+    .map_err(|e| AppError::Internal(format!("Error hashing password: {}", e)))
+}
+
+pub async fn verify_password(password: String, hash: String) -> Result<bool, AppError> {
+    web::block(move || {
+        let hash = PasswordHash::new(&hash)?;
+        match Argon2::default().verify_password(password.as_bytes(), &hash) {
+            Ok(()) => Ok(true),
+            Err(argon2::password_hash::Error::Password) => Ok(false),
+            Err(e) => Err(e),
+        }
+    })
+    .await
+    .map_err(|e| AppError::Internal(format!("Error verifying password: {}", e)))?
+    .map_err(|e| AppError::Internal(format!("Error verifying password: {}", e)))
+}
+
+/// Refresh tokens are opaque random strings. Only their SHA-256 is stored, so
+/// a leaked database can't be used to mint sessions. Each login starts a
+/// family of tokens; every refresh revokes the presented token and issues the
+/// next one in the family.
+pub fn issue_refresh_token(
+    conn: &Connection,
+    keys: &JwtKeys,
+    user_id: &str,
+    family_id: Option<&str>,
+) -> Result<String, AppError> {
+    let mut bytes = [0u8; REFRESH_TOKEN_BYTES];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let token = hex(&bytes);
+
+    let family_id = match family_id {
+        Some(family_id) => family_id.to_string(),
+        None => uuid::Uuid::new_v4().to_string(),
+    };
+    let expires_at = (unix_now() + keys.refresh_ttl).as_secs();
+
+    conn.execute(
+        "INSERT INTO refresh_tokens (token_hash, user_id, family_id, expires_at)
+         VALUES (?1, ?2, ?3, ?4)",
+        params![hash_token(&token), user_id, family_id, expires_at],
+    )?;
+
+    Ok(token)
+}
+
+/// Exchanges a refresh token for the next one in its family, returning the
+/// user id and the new token. Presenting a token that was already rotated
+/// means it has leaked, so the whole family is revoked.
+pub fn rotate_refresh_token(
+    conn: &mut Connection,
+    keys: &JwtKeys,
+    token: &str,
+) -> Result<(String, String), AppError> {
+    let invalid = || AppError::Unauthorized("Invalid refresh token".to_string());
+    let token_hash = hash_token(token);
+    // Under one write lock, so two refreshes with the same token can't both
+    // rotate it, and neither fails for want of upgrading a read lock.
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+    let (user_id, family_id, expires_at, revoked): (String, String, u64, bool) = tx
+        .query_row(
+            "SELECT user_id, family_id, expires_at, revoked FROM refresh_tokens
+             WHERE token_hash = ?1",
+            params![token_hash],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )
+        .optional()?
+        .ok_or_else(invalid)?;
+
+    if revoked {
+        eprintln!(
+            "Refresh token reused; revoking session for user {}",
+            user_id
+        );
+        tx.execute(
+            "UPDATE refresh_tokens SET revoked = 1 WHERE family_id = ?1",
+            params![family_id],
+        )?;
+        tx.commit()?;
+        return Err(invalid());
+    }
+
+    if expires_at <= unix_now().as_secs() {
+        return Err(AppError::Unauthorized(
+            "Refresh token has expired".to_string(),
+        ));
+    }
+
+    tx.execute(
+        "UPDATE refresh_tokens SET revoked = 1 WHERE token_hash = ?1",
+        params![token_hash],
+    )?;
+    let next = issue_refresh_token(&tx, keys, &user_id, Some(&family_id))?;
+    tx.commit()?;
+
+    Ok((user_id, next))
+}
+
+/// Revokes the session the refresh token belongs to. Unknown tokens are
+/// ignored so logging out twice is harmless.
+pub fn revoke_refresh_token(conn: &Connection, token: &str) -> Result<(), AppError> {
+    conn.execute(
+        "UPDATE refresh_tokens SET revoked = 1 WHERE family_id IN (
+             SELECT family_id FROM refresh_tokens WHERE token_hash = ?1
+         )",
+        params![hash_token(token)],
+    )?;
+
+    Ok(())
+}
+
+fn hash_token(token: &str) -> String {
+    hex(&Sha256::digest(token.as_bytes()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The user a request was made by, taken from its `Authorization: Bearer`
+/// token. Handlers that take this reject unauthenticated requests, and those
+/// from deleted accounts, with 401.
+pub struct AuthenticatedUser {
+    pub user_id: String,
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = AppError;
+    type Future = LocalBoxFuture<'static, Result<Self, AppError>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let user = authenticate(req);
+        let db = req.app_data::<web::Data<Database>>().cloned();
+
+        Box::pin(async move { ensure_active(db, user?).await })
+    }
+}
+
+/// Like [`AuthenticatedUser`], but lets requests without an `Authorization`
+/// header through as `None`. A token that is present but invalid is still
+/// rejected, so a bad token is never mistaken for an anonymous request.
+pub struct MaybeAuthenticated(pub Option<AuthenticatedUser>);
+
+impl FromRequest for MaybeAuthenticated {
+    type Error = AppError;
+    type Future = LocalBoxFuture<'static, Result<Self, AppError>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let user = if req.headers().contains_key(AUTHORIZATION) {
+            authenticate(req).map(Some)
+        } else {
+            Ok(None)
+        };
+        let db = req.app_data::<web::Data<Database>>().cloned();
+
+        Box::pin(async move {
+            let user = match user? {
+                Some(user) => Some(ensure_active(db, user).await?),
+                None => None,
+            };
+            Ok(MaybeAuthenticated(user))
+        })
+    }
+}
+
+/// The user behind a WebSocket request, if it carries a token. Browsers
+/// can't set headers on WebSocket requests, so besides the `Authorization`
+/// header the token may come as `?token=...`. As with [`MaybeAuthenticated`],
+/// an invalid token is rejected.
+pub struct SocketUser(pub Option<AuthenticatedUser>);
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+impl FromRequest for SocketUser {
+    type Error = AppError;
+    type Future = LocalBoxFuture<'static, Result<Self, AppError>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let query_token = web::Query::<TokenQuery>::from_query(req.query_string())
+            .ok()
+            .and_then(|query| query.into_inner().token);
+
+        let user = if req.headers().contains_key(AUTHORIZATION) {
+            authenticate(req).map(Some)
+        } else if let Some(token) = query_token {
+            verify_with_app_keys(req, &token).map(Some)
+        } else {
+            Ok(None)
+        };
+
+        let db = req.app_data::<web::Data<Database>>().cloned();
+
+        Box::pin(async move {
+            let user = match user? {
+                Some(user) => Some(ensure_active(db, user).await?),
+                None => None,
+            };
+            Ok(SocketUser(user))
+        })
+    }
+}
+
+/// Rejects tokens that outlive their account: a signed token stays valid
+/// until it expires, even after the user deletes their account.
+async fn ensure_active(
+    db: Option<web::Data<Database>>,
+    user: AuthenticatedUser,
+) -> Result<AuthenticatedUser, AppError> {
+    let db = db.ok_or_else(|| AppError::Internal("Database is not configured".to_string()))?;
+
+    let user_id = user.user_id.clone();
+    let active = db
+        .run(move |conn| {
+            conn.query_row(
+                "SELECT EXISTS (SELECT 1 FROM users WHERE id = ?1 AND deleted_at IS NULL)",
+                params![user_id],
+                |row| row.get::<_, bool>(0),
+            )
+            .map_err(AppError::from)
+        })
+        .await?;
+
+    if active {
+        Ok(user)
+    } else {
+        Err(AppError::Unauthorized("Unknown user".to_string()))
+    }
+}
+
+fn authenticate(req: &HttpRequest) -> Result<AuthenticatedUser, AppError> {
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(|| AppError::Unauthorized("Missing bearer token".to_string()))?;
+
+    verify_with_app_keys(req, token)
+}
+
+fn verify_with_app_keys(req: &HttpRequest, token: &str) -> Result<AuthenticatedUser, AppError> {
+    let keys = req
+        .app_data::<web::Data<JwtKeys>>()
+        .ok_or_else(|| AppError::Internal("JWT keys are not configured".to_string()))?;
+
+    let user_id = keys.verify(token.trim())?;
+
+    Ok(AuthenticatedUser { user_id })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations;
+
+    const SECRET: &[u8] = b"0123456789abcdef0123456789abcdef";
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    fn keys() -> JwtKeys {
+        JwtKeys::new(SECRET, HOUR, HOUR)
+    }
+
+    fn database() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("PRAGMA foreign_keys = ON;").unwrap();
+        migrations::run(&mut conn).unwrap();
+        conn.execute(
+            "INSERT INTO users (id, username) VALUES ('user', 'alice')",
+            [],
+        )
+        .unwrap();
+        conn
+    }
+
+    fn unauthorized(result: Result<impl Sized, AppError>) -> String {
+        match result {
+            Err(AppError::Unauthorized(message)) => message,
+            Err(e) => panic!("expected unauthorized, got {}", e),
+            Ok(_) => panic!("expected unauthorized, got success"),
+        }
+    }
+
+    #[test]
+    fn issued_tokens_verify_as_their_user() {
+        let keys = keys();
+        let token = keys.issue("user").unwrap();
+        assert_eq!(keys.verify(&token).unwrap(), "user");
+    }
+
+    #[test]
+    fn tampered_and_foreign_tokens_are_rejected() {
+        let token = keys().issue("user").unwrap();
+
+        let other = JwtKeys::new(b"another secret of at least 32 bytes", HOUR, HOUR);
+        assert_eq!(unauthorized(other.verify(&token)), "Invalid token");
+
+        let mut tampered = token.clone();
+        tampered.pop();
+        assert_eq!(unauthorized(keys().verify(&tampered)), "Invalid token");
+        assert_eq!(unauthorized(keys().verify("not a token")), "Invalid token");
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected() {
+        let now = unix_now().as_secs();
+        let claims = Claims {
+            sub: "user".to_string(),
+            iat: now - 2 * HOUR.as_secs(),
+            exp: now - HOUR.as_secs(),
+        };
+        let token = jsonwebtoken::encode(
+            &Header::new(Algorithm::HS256),
+            &claims,
+            &EncodingKey::from_secret(SECRET),
+        )
+        .unwrap();
+
+        assert_eq!(unauthorized(keys().verify(&token)), "Token has expired");
+    }
+
+    #[test]
+    fn refresh_tokens_rotate_within_their_family() {
+        let mut conn = database();
+        let keys = keys();
+        let first = issue_refresh_token(&conn, &keys, "user", None).unwrap();
+
+        let (user_id, second) = rotate_refresh_token(&mut conn, &keys, &first).unwrap();
+        assert_eq!(user_id, "user");
+        assert_ne!(second, first);
+
+        let (_, third) = rotate_refresh_token(&mut conn, &keys, &second).unwrap();
+        assert_ne!(third, second);
+
+        let families: i64 = conn
+            .query_row(
+                "SELECT COUNT(DISTINCT family_id) FROM refresh_tokens",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(families, 1);
+    }
+
+    #[test]
+    fn reusing_a_rotated_refresh_token_revokes_its_family() {
+        let mut conn = database();
+        let keys = keys();
+        let stolen = issue_refresh_token(&conn, &keys, "user", None).unwrap();
+        let other_session = issue_refresh_token(&conn, &keys, "user", None).unwrap();
+        let (_, current) = rotate_refresh_token(&mut conn, &keys, &stolen).unwrap();
+
+        assert_eq!(
+            unauthorized(rotate_refresh_token(&mut conn, &keys, &stolen)),
+            "Invalid refresh token"
+        );
+        assert_eq!(
+            unauthorized(rotate_refresh_token(&mut conn, &keys, &current)),
+            "Invalid refresh token"
+        );
+
+        // The user's other logins carry on.
+        assert!(rotate_refresh_token(&mut conn, &keys, &other_session).is_ok());
+    }
+
+    #[test]
+    fn expired_and_unknown_refresh_tokens_are_rejected() {
+        let mut conn = database();
+        let keys = JwtKeys::new(SECRET, HOUR, Duration::ZERO);
+        let token = issue_refresh_token(&conn, &keys, "user", None).unwrap();
+
+        assert_eq!(
+            unauthorized(rotate_refresh_token(&mut conn, &keys, &token)),
+            "Refresh token has expired"
+        );
+        assert_eq!(
+            unauthorized(rotate_refresh_token(&mut conn, &keys, "unknown")),
+            "Invalid refresh token"
+        );
+    }
+
+    #[test]
+    fn logging_out_revokes_the_session() {
+        let mut conn = database();
+        let keys = keys();
+        let first = issue_refresh_token(&conn, &keys, "user", None).unwrap();
+        let (_, second) = rotate_refresh_token(&mut conn, &keys, &first).unwrap();
+
+        revoke_refresh_token(&conn, &first).unwrap();
+
+        assert_eq!(
+            unauthorized(rotate_refresh_token(&mut conn, &keys, &second)),
+            "Invalid refresh token"
+        );
+    }
+}
@@ -0,0 +1,458 @@
+// config.rs
+use crate::auth::MIN_SECRET_LEN;
+use crate::game_state::{
+    ImageModel, MAX_PHASE_SECONDS, MAX_PLAYERS, MAX_TOTAL_ROUNDS, MIN_PHASE_SECONDS,
+};
+use serde::Deserialize;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+// Every game needs an author and someone to guess.
+const MIN_PLAYERS: usize = 2;
+
+/// Server settings, read from a TOML file and then overridden by environment
+/// variables (including those in `.env`). Every field has a default, so the
+/// file and each of its sections are optional.
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub models: ModelConfig,
+    pub game: GameConfig,
+    pub ai: AiConfig,
+    pub auth: AuthConfig,
+    pub usernames: UsernameConfig,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind_address: String,
+    /// Origins browsers may call the API from, e.g. `https://guess-ai.app`.
+    pub cors_origins: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub path: String,
+}
+
+/// The models used where the server picks one itself rather than taking it
+/// from the request.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelConfig {
+    pub transcription: String,
+    pub embedding: String,
+    /// The cosine similarity the embedding model gives unrelated texts.
+    /// Guesses are scored on how far above it they are.
+    pub embedding_baseline: f64,
+    /// Illustrates round prompts and avatars.
+    pub image: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct GameConfig {
+    pub total_rounds: i32,
+    /// Players needed before the host can start a game.
+    pub min_players: usize,
+    pub max_players: usize,
+    pub imagining_seconds: u32,
+    pub guessing_seconds: u32,
+    /// How long a player who lost their connection has to come back before
+    /// they are removed from the game.
+    pub reconnect_grace_seconds: u64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AiConfig {
+    pub backend: AiBackend,
+    /// Better set through `OPENAI_API_KEY` than kept in the file. OpenAI
+    /// models are unavailable without it.
+    pub openai_api_key: Option<String>,
+    pub openai_base_url: String,
+    /// Enables Claude models.
+    pub anthropic_api_key: Option<String>,
+    pub anthropic_base_url: String,
+}
+
+/// Where AI requests are served from.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum AiBackend {
+    /// The providers whose API keys are configured.
+    Live,
+    /// The offline mock provider, for every model.
+    Mock,
+}
+
+impl FromStr for AiBackend {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "live" => Ok(AiBackend::Live),
+            "mock" => Ok(AiBackend::Mock),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Better set through `JWT_SECRET` than kept in the file.
+    pub jwt_secret: Option<String>,
+    pub token_ttl_seconds: u64,
+    pub refresh_token_ttl_seconds: u64,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct UsernameConfig {
+    /// Blocked on top of the built-in list.
+    pub blocklist: Vec<String>,
+    pub blocklist_file: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind_address: "127.0.0.1:8080".to_string(),
+            cors_origins: vec![
+                "https://guess-ai.app".to_string(),
+                "http://localhost:3000".to_string(),
+            ],
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            path: "game_database.db".to_string(),
+        }
+    }
+}
+
+impl Default for ModelConfig {
+    fn default() -> Self {
+        ModelConfig {
+            transcription: "whisper-1".to_string(),
+            embedding: "text-embedding-ada-002".to_string(),
+            // Unrelated phrases typically land around 0.7 with ada-002.
+            embedding_baseline: 0.7,
+            image: "dall-e-3".to_string(),
+        }
+    }
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        GameConfig {
+            total_rounds: 3,
+            min_players: 2,
+            max_players: 8,
+            imagining_seconds: 90,
+            guessing_seconds: 60,
+            reconnect_grace_seconds: 120,
+        }
+    }
+}
+
+impl Default for AiConfig {
+    fn default() -> Self {
+        AiConfig {
+            backend: AiBackend::Live,
+            openai_api_key: None,
+            openai_base_url: "https://api.openai.com/v1".to_string(),
+            anthropic_api_key: None,
+            anthropic_base_url: "https://api.anthropic.com/v1".to_string(),
+        }
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            jwt_secret: None,
+            // Access tokens are short-lived; clients renew them with a
+            // refresh token.
+            token_ttl_seconds: 15 * 60,
+            refresh_token_ttl_seconds: 30 * 24 * 60 * 60,
+        }
+    }
+}
+
+/// Everything wrong with the configuration, so it can all be fixed at once.
+#[derive(Debug)]
+pub struct ConfigError(Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid configuration:")?;
+        for problem in &self.0 {
+            write!(f, "\n  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+    /// Reads the file named by `CONFIG_FILE`, or `config.toml` if it exists,
+    /// applies environment overrides and validates the result.
+    pub fn load() -> Result<Self, ConfigError> {
+        let mut config = match env::var("CONFIG_FILE") {
+            Ok(path) => Self::from_file(&path, true)?,
+            Err(_) => Self::from_file(DEFAULT_CONFIG_FILE, false)?,
+        };
+
+        let mut problems = config.apply_env();
+        problems.extend(config.validate());
+
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError(problems))
+        }
+    }
+
+    fn from_file(path: &str, required: bool) -> Result<Self, ConfigError> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !required => {
+                return Ok(Config::default())
+            }
+            Err(e) => return Err(ConfigError(vec![format!("Could not read {}: {}", path, e)])),
+        };
+
+        toml::from_str(&contents).map_err(|e| ConfigError(vec![format!("{}: {}", path, e)]))
+    }
+
+    /// Applies the environment variables that override the file, returning
+    /// those that couldn't be parsed.
+    fn apply_env(&mut self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        override_from_env("BIND_ADDRESS", &mut self.server.bind_address, &mut problems);
+        if let Ok(origins) = env::var("CORS_ORIGINS") {
+            self.server.cors_origins = split_list(&origins);
+        }
+        override_from_env("DATABASE_PATH", &mut self.database.path, &mut problems);
+        override_from_env(
+            "TRANSCRIPTION_MODEL",
+            &mut self.models.transcription,
+            &mut problems,
+        );
+        override_from_env("EMBEDDING_MODEL", &mut self.models.embedding, &mut problems);
+        override_from_env(
+            "EMBEDDING_BASELINE",
+            &mut self.models.embedding_baseline,
+            &mut problems,
+        );
+        override_from_env("IMAGE_MODEL", &mut self.models.image, &mut problems);
+        override_from_env("TOTAL_ROUNDS", &mut self.game.total_rounds, &mut problems);
+        override_from_env("MIN_PLAYERS", &mut self.game.min_players, &mut problems);
+        override_from_env("MAX_PLAYERS", &mut self.game.max_players, &mut problems);
+        override_from_env(
+            "IMAGINING_SECONDS",
+            &mut self.game.imagining_seconds,
+            &mut problems,
+        );
+        override_from_env(
+            "GUESSING_SECONDS",
+            &mut self.game.guessing_seconds,
+            &mut problems,
+        );
+        override_from_env(
+            "RECONNECT_GRACE_SECONDS",
+            &mut self.game.reconnect_grace_seconds,
+            &mut problems,
+        );
+        override_from_env("AI_BACKEND", &mut self.ai.backend, &mut problems);
+        if let Ok(key) = env::var("OPENAI_API_KEY") {
+            self.ai.openai_api_key = Some(key);
+        }
+        override_from_env(
+            "OPENAI_BASE_URL",
+            &mut self.ai.openai_base_url,
+            &mut problems,
+        );
+        if let Ok(key) = env::var("ANTHROPIC_API_KEY") {
+            self.ai.anthropic_api_key = Some(key);
+        }
+        override_from_env(
+            "ANTHROPIC_BASE_URL",
+            &mut self.ai.anthropic_base_url,
+            &mut problems,
+        );
+        if let Ok(secret) = env::var("JWT_SECRET") {
+            self.auth.jwt_secret = Some(secret);
+        }
+        override_from_env(
+            "JWT_TTL_SECONDS",
+            &mut self.auth.token_ttl_seconds,
+            &mut problems,
+        );
+        override_from_env(
+            "REFRESH_TOKEN_TTL_SECONDS",
+            &mut self.auth.refresh_token_ttl_seconds,
+            &mut problems,
+        );
+        if let Ok(words) = env::var("USERNAME_BLOCKLIST") {
+            self.usernames.blocklist = split_list(&words);
+        }
+        if let Ok(path) = env::var("USERNAME_BLOCKLIST_FILE") {
+            self.usernames.blocklist_file = Some(path);
+        }
+
+        problems
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if self.server.bind_address.parse::<SocketAddr>().is_err() {
+            problems.push(format!(
+                "server.bind_address must be an address and port like 127.0.0.1:8080, not {:?}",
+                self.server.bind_address
+            ));
+        }
+        for origin in &self.server.cors_origins {
+            let scheme_ok = origin.starts_with("http://") || origin.starts_with("https://");
+            if !scheme_ok || origin.ends_with('/') {
+                problems.push(format!(
+                    "server.cors_origins entry {:?} must be a scheme and host like https://example.com",
+                    origin
+                ));
+            }
+        }
+
+        if self.database.path.trim().is_empty() {
+            problems.push("database.path must not be empty".to_string());
+        }
+
+        for (name, model) in [
+            ("models.transcription", &self.models.transcription),
+            ("models.embedding", &self.models.embedding),
+            ("models.image", &self.models.image),
+        ] {
+            if model.trim().is_empty() {
+                problems.push(format!("{} must not be empty", name));
+            }
+        }
+
+        if ImageModel::find(&self.models.image).is_none() {
+            problems.push(format!(
+                "models.image must be one of {}, not {}",
+                ImageModel::names(),
+                self.models.image
+            ));
+        }
+
+        if !(0.0..1.0).contains(&self.models.embedding_baseline) {
+            problems.push(format!(
+                "models.embedding_baseline must be at least 0 and below 1, not {}",
+                self.models.embedding_baseline
+            ));
+        }
+
+        if !(1..=MAX_TOTAL_ROUNDS).contains(&self.game.total_rounds) {
+            problems.push(format!(
+                "game.total_rounds must be between 1 and {}, not {}",
+                MAX_TOTAL_ROUNDS, self.game.total_rounds
+            ));
+        }
+
+        if self.game.min_players < MIN_PLAYERS {
+            problems.push(format!(
+                "game.min_players must be at least {}, not {}",
+                MIN_PLAYERS, self.game.min_players
+            ));
+        }
+        if self.game.max_players > MAX_PLAYERS || self.game.max_players < self.game.min_players {
+            problems.push(format!(
+                "game.max_players must be between game.min_players and {}, not {}",
+                MAX_PLAYERS, self.game.max_players
+            ));
+        }
+        for (name, seconds) in [
+            ("game.imagining_seconds", self.game.imagining_seconds),
+            ("game.guessing_seconds", self.game.guessing_seconds),
+        ] {
+            if !(MIN_PHASE_SECONDS..=MAX_PHASE_SECONDS).contains(&seconds) {
+                problems.push(format!(
+                    "{} must be between {} and {}, not {}",
+                    name, MIN_PHASE_SECONDS, MAX_PHASE_SECONDS, seconds
+                ));
+            }
+        }
+
+        if self.game.reconnect_grace_seconds == 0 {
+            problems.push("game.reconnect_grace_seconds must be greater than 0".to_string());
+        }
+
+        for (name, key) in [
+            ("ai.openai_api_key", &self.ai.openai_api_key),
+            ("ai.anthropic_api_key", &self.ai.anthropic_api_key),
+        ] {
+            if key.as_ref().is_some_and(|key| key.trim().is_empty()) {
+                problems.push(format!("{} must not be empty when set", name));
+            }
+        }
+        for (name, url) in [
+            ("ai.openai_base_url", &self.ai.openai_base_url),
+            ("ai.anthropic_base_url", &self.ai.anthropic_base_url),
+        ] {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                problems.push(format!(
+                    "{} must be an http:// or https:// URL, not {:?}",
+                    name, url
+                ));
+            }
+        }
+
+        if let Some(secret) = &self.auth.jwt_secret {
+            if secret.len() < MIN_SECRET_LEN {
+                problems.push(format!(
+                    "auth.jwt_secret must be at least {} bytes long",
+                    MIN_SECRET_LEN
+                ));
+            }
+        }
+        if self.auth.token_ttl_seconds == 0 {
+            problems.push("auth.token_ttl_seconds must be greater than 0".to_string());
+        }
+        if self.auth.refresh_token_ttl_seconds == 0 {
+            problems.push("auth.refresh_token_ttl_seconds must be greater than 0".to_string());
+        }
+
+        problems
+    }
+}
+
+fn override_from_env<T: FromStr>(name: &str, target: &mut T, problems: &mut Vec<String>) {
+    if let Ok(value) = env::var(name) {
+        match value.trim().parse() {
+            Ok(parsed) => *target = parsed,
+            Err(_) => problems.push(format!("{} has an invalid value: {:?}", name, value)),
+        }
+    }
+}
+
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
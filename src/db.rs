@@ -0,0 +1,61 @@
+// db.rs
+use crate::error::AppError;
+use actix_web::web;
+use r2d2::{Pool, PooledConnection};
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::Connection;
+use std::time::Duration;
+
+const POOL_SIZE: u32 = 16;
+// How long a connection waits on another writer before giving up with
+// `database is locked`.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+const CHECKOUT_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub type DbConnection = PooledConnection<SqliteConnectionManager>;
+
+/// A pool of SQLite connections shared by all handlers. Connections use WAL
+/// so readers don't block the writer, and enforce foreign keys.
+#[derive(Clone)]
+pub struct Database {
+    pool: Pool<SqliteConnectionManager>,
+}
+
+impl Database {
+    pub fn open(path: &str) -> Result<Self, r2d2::Error> {
+        let manager = SqliteConnectionManager::file(path).with_init(|conn| {
+            conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA foreign_keys = ON;")?;
+            conn.busy_timeout(BUSY_TIMEOUT)
+        });
+
+        let pool = Pool::builder()
+            .max_size(POOL_SIZE)
+            .connection_timeout(CHECKOUT_TIMEOUT)
+            .build(manager)?;
+
+        Ok(Database { pool })
+    }
+
+    pub fn get(&self) -> Result<DbConnection, AppError> {
+        self.pool
+            .get()
+            .map_err(|e| AppError::Internal(format!("Could not get a database connection: {}", e)))
+    }
+
+    /// Runs `f` on a pooled connection on the blocking thread pool, so slow
+    /// queries and lock waits don't stall the async workers.
+    pub async fn run<T, F>(&self, f: F) -> Result<T, AppError>
+    where
+        F: FnOnce(&mut Connection) -> Result<T, AppError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let db = self.clone();
+
+        web::block(move || {
+            let mut conn = db.get()?;
+            f(&mut conn)
+        })
+        .await
+        .map_err(|e| AppError::Internal(format!("Database task failed: {}", e)))?
+    }
+}
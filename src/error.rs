@@ -0,0 +1,233 @@
+// error.rs
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fmt;
+
+/// Errors surfaced to clients as `{"error": {"code": ..., "message": ...}}`
+/// so they can branch on the code rather than parse messages.
+#[derive(Debug)]
+pub enum AppError {
+    BadRequest(String),
+    /// Input that failed validation, with a reason for each offending field.
+    Validation(Vec<FieldError>),
+    Unauthorized(String),
+    /// Authenticated, but not allowed to do this.
+    Forbidden(String),
+    NotFound(String),
+    Conflict(String),
+    RateLimited {
+        message: String,
+        retry_after: Option<u64>,
+    },
+    ContentPolicy(String),
+    UpstreamUnavailable(String),
+    Internal(String),
+}
+
+#[derive(Serialize, Debug)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &'static str, code: &'static str, message: String) -> Self {
+        FieldError {
+            field,
+            code,
+            message,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ProviderErrorBody {
+    error: ProviderErrorDetail,
+}
+
+/// The error object shared by OpenAI (`message`, `type`, `code`) and
+/// Anthropic (`message`, `type`) error responses.
+#[derive(Deserialize, Default)]
+struct ProviderErrorDetail {
+    #[serde(default)]
+    message: String,
+    #[serde(default, rename = "type")]
+    kind: Option<String>,
+    #[serde(default)]
+    code: Option<String>,
+}
+
+impl AppError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::BadRequest(_) => "bad_request",
+            AppError::Validation(_) => "validation_failed",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::NotFound(_) => "not_found",
+            AppError::Conflict(_) => "conflict",
+            AppError::RateLimited { .. } => "rate_limited",
+            AppError::ContentPolicy(_) => "content_policy",
+            AppError::UpstreamUnavailable(_) => "upstream_unavailable",
+            AppError::Internal(_) => "internal",
+        }
+    }
+
+    /// Maps a non-success response from an AI provider onto an error code,
+    /// using the provider's error type where the status alone is ambiguous.
+    pub fn from_upstream(status: u16, retry_after: Option<u64>, body: &str) -> Self {
+        let detail = serde_json::from_str::<ProviderErrorBody>(body)
+            .map(|body| body.error)
+            .unwrap_or_default();
+
+        let message = if detail.message.is_empty() {
+            format!("AI provider responded with HTTP {}", status)
+        } else {
+            detail.message.clone()
+        };
+
+        let kind = detail.code.as_deref().or(detail.kind.as_deref());
+        match Self::from_provider_kind(kind, &message) {
+            Some(AppError::RateLimited { message, .. }) => {
+                return AppError::RateLimited {
+                    message,
+                    retry_after,
+                }
+            }
+            Some(error) => return error,
+            None => {}
+        }
+
+        match status {
+            429 => AppError::RateLimited {
+                message,
+                retry_after,
+            },
+            401 | 403 => {
+                eprintln!("Provider rejected credentials: {}", message);
+                AppError::UpstreamUnavailable("AI provider rejected our credentials".to_string())
+            }
+            400..=499 => AppError::BadRequest(message),
+            _ => AppError::UpstreamUnavailable(message),
+        }
+    }
+
+    /// Maps a provider error type, as found in error bodies and in-stream
+    /// error events, onto an error code. Returns `None` for types that don't
+    /// determine the code on their own.
+    pub fn from_provider_kind(kind: Option<&str>, message: &str) -> Option<Self> {
+        let error = match kind? {
+            "content_policy_violation" | "content_filter" => {
+                AppError::ContentPolicy(message.to_string())
+            }
+            "rate_limit_error" | "rate_limit_exceeded" => AppError::RateLimited {
+                message: message.to_string(),
+                retry_after: None,
+            },
+            // A 429 for an exhausted quota won't clear up by retrying.
+            "insufficient_quota" | "overloaded_error" | "api_error" => {
+                AppError::UpstreamUnavailable(message.to_string())
+            }
+            _ => return None,
+        };
+
+        Some(error)
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::BadRequest(message)
+            | AppError::Unauthorized(message)
+            | AppError::Forbidden(message)
+            | AppError::NotFound(message)
+            | AppError::Conflict(message)
+            | AppError::RateLimited { message, .. }
+            | AppError::ContentPolicy(message)
+            | AppError::UpstreamUnavailable(message)
+            | AppError::Internal(message) => write!(f, "{}", message),
+            AppError::Validation(errors) => {
+                let messages: Vec<&str> = errors.iter().map(|e| e.message.as_str()).collect();
+                write!(f, "{}", messages.join("; "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::BadRequest(_) | AppError::Validation(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::ContentPolicy(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::UpstreamUnavailable(_) => StatusCode::BAD_GATEWAY,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        // Internal details stay in the log; clients get a generic message.
+        let message = match self {
+            AppError::Internal(message) => {
+                eprintln!("Error: {}", message);
+                "Internal server error".to_string()
+            }
+            _ => self.to_string(),
+        };
+
+        let mut response = HttpResponse::build(self.status_code());
+        if let AppError::RateLimited {
+            retry_after: Some(seconds),
+            ..
+        } = self
+        {
+            response.insert_header(("Retry-After", seconds.to_string()));
+        }
+
+        let mut error = json!({
+            "code": self.code(),
+            "message": message,
+        });
+        if let AppError::Validation(fields) = self {
+            error["fields"] = json!(fields);
+        }
+
+        response.json(json!({ "error": error }))
+    }
+}
+
+/// Error handler for the JSON, query and path extractors, which would
+/// otherwise reject malformed requests with a plain-text 400.
+pub fn extractor_error<E: fmt::Display>(e: E, _req: &HttpRequest) -> actix_web::Error {
+    AppError::BadRequest(e.to_string()).into()
+}
+
+impl From<reqwest::Error> for AppError {
+    fn from(e: reqwest::Error) -> Self {
+        eprintln!("Error calling AI provider: {}", e);
+
+        if e.is_timeout() {
+            AppError::UpstreamUnavailable("AI provider timed out".to_string())
+        } else if e.is_decode() {
+            AppError::UpstreamUnavailable("Unexpected response from AI provider".to_string())
+        } else {
+            AppError::UpstreamUnavailable("Could not reach AI provider".to_string())
+        }
+    }
+}
+
+impl From<rusqlite::Error> for AppError {
+    fn from(e: rusqlite::Error) -> Self {
+        AppError::Internal(format!("Database error: {}", e))
+    }
+}
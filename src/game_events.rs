@@ -0,0 +1,184 @@
+// game_events.rs
+use crate::game_state::{GamePhase, GameState, Presence};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+// Updates a slow subscriber can fall behind by before it is sent a fresh
+// snapshot instead.
+const CHANNEL_CAPACITY: usize = 32;
+
+#[derive(Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum GameEvent {
+    Snapshot,
+    PlayerJoined {
+        player_id: String,
+    },
+    PlayerLeft {
+        player_id: String,
+    },
+    PlayerKicked {
+        player_id: String,
+    },
+    PlayerReady {
+        player_id: String,
+        ready: bool,
+    },
+    PresenceChanged {
+        player_id: String,
+        presence: Presence,
+    },
+    /// Players removed after staying disconnected too long.
+    PlayersDropped {
+        player_ids: Vec<String>,
+    },
+    PromptSubmitted {
+        player_id: String,
+    },
+    PhaseChanged {
+        from: GamePhase,
+        to: GamePhase,
+    },
+    /// `phase` ran out of time; `player_ids` are the players marked AFK.
+    PhaseTimedOut {
+        phase: GamePhase,
+        player_ids: Vec<String>,
+    },
+    ImageReady {
+        round: i32,
+    },
+    GuessScored {
+        player_id: String,
+        score: u32,
+    },
+    RoundOver {
+        round: i32,
+    },
+}
+
+/// An event pushed to a game's subscribers, along with the game state after
+/// it happened.
+#[derive(Serialize)]
+pub(crate) struct GameUpdate {
+    #[serde(flatten)]
+    pub(crate) event: GameEvent,
+    pub(crate) state: Arc<GameState>,
+}
+
+/// What a game looked like before a handler mutated it, used to work out
+/// which follow-on events the mutation caused.
+pub(crate) struct Checkpoint {
+    phase: GamePhase,
+    rounds_completed: usize,
+}
+
+impl Checkpoint {
+    pub(crate) fn of(game_state: &GameState) -> Self {
+        Checkpoint {
+            phase: game_state.status,
+            rounds_completed: game_state.round_results.len(),
+        }
+    }
+}
+
+/// Per-game broadcast channels feeding the `/ws/game/{uuid}` sockets.
+#[derive(Default)]
+pub(crate) struct GameEvents {
+    channels: Mutex<HashMap<String, broadcast::Sender<Arc<GameUpdate>>>>,
+    /// Open sockets per `(game, player)`, since a player may have several.
+    connections: Mutex<HashMap<(String, String), usize>>,
+}
+
+impl GameEvents {
+    /// Counts a player's socket opening. Returns true for their first one.
+    pub(crate) fn connect(&self, game_id: &str, player_id: &str) -> bool {
+        let mut connections = self.connections.lock().unwrap();
+        let count = connections
+            .entry((game_id.to_string(), player_id.to_string()))
+            .or_insert(0);
+        *count += 1;
+        *count == 1
+    }
+
+    /// Counts a player's socket closing. Returns true once none are left.
+    pub(crate) fn disconnect(&self, game_id: &str, player_id: &str) -> bool {
+        let mut connections = self.connections.lock().unwrap();
+        let key = (game_id.to_string(), player_id.to_string());
+        match connections.get_mut(&key) {
+            Some(count) if *count > 1 => {
+                *count -= 1;
+                false
+            }
+            _ => {
+                connections.remove(&key);
+                true
+            }
+        }
+    }
+
+    pub(crate) fn subscribe(&self, game_id: &str) -> broadcast::Receiver<Arc<GameUpdate>> {
+        self.channels
+            .lock()
+            .unwrap()
+            .entry(game_id.to_string())
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .subscribe()
+    }
+
+    /// Drops a game's channel if nobody is subscribed to it, as after a
+    /// socket for a game that doesn't exist.
+    pub(crate) fn release(&self, game_id: &str) {
+        let mut channels = self.channels.lock().unwrap();
+        if channels
+            .get(game_id)
+            .is_some_and(|sender| sender.receiver_count() == 0)
+        {
+            channels.remove(game_id);
+        }
+    }
+
+    /// Publishes `event` followed by any round-over and phase-change events
+    /// implied by the difference between `before` and `game_state`.
+    pub(crate) fn publish(
+        &self,
+        before: Checkpoint,
+        game_state: &GameState,
+        event: Option<GameEvent>,
+    ) {
+        let mut events: Vec<GameEvent> = event.into_iter().collect();
+
+        if let Some(result) = game_state.round_results.get(before.rounds_completed) {
+            events.push(GameEvent::RoundOver {
+                round: result.round,
+            });
+        }
+
+        if before.phase != game_state.status {
+            events.push(GameEvent::PhaseChanged {
+                from: before.phase,
+                to: game_state.status,
+            });
+        }
+
+        let mut channels = self.channels.lock().unwrap();
+        let Some(sender) = channels.get(&game_state.game_id) else {
+            return;
+        };
+
+        let state = Arc::new(game_state.clone());
+        for event in events {
+            let update = GameUpdate {
+                event,
+                state: state.clone(),
+            };
+
+            if sender.send(Arc::new(update)).is_err() {
+                // Nobody is listening any more.
+                channels.remove(&game_state.game_id);
+                return;
+            }
+        }
+    }
+}
@@ -1,46 +1,91 @@
 // game_handlers.rs
-use crate::ai_handlers;
-use actix_web::{web, HttpResponse, Responder};
+use crate::ai_handlers::{self, ImageRequestPayload};
+use crate::auth::{unix_now, AuthenticatedUser, JwtKeys, MaybeAuthenticated, SocketUser};
+use crate::config::Config;
+use crate::db::Database;
+use crate::error::AppError;
+use crate::error::FieldError;
+use crate::game_events::{GameEvent, GameEvents, GameUpdate};
+use crate::game_state::{
+    GameAction, GamePhase, GameSettings, GameState, ImageModel, Player, PlayerView, Presence,
+    ScoringMode, Visibility, GENERATING_SECONDS, MAX_PHASE_SECONDS, MAX_PLAYERS, MAX_TOTAL_ROUNDS,
+    MIN_PHASE_SECONDS,
+};
+use crate::game_store::{self, load_game_state, update_game};
+use crate::providers::ProviderRegistry;
+use crate::user_handlers::{self, SessionResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_ws::Message;
+use futures::StreamExt;
 use rand::Rng;
-use rusqlite::{params, Connection};
+use rusqlite::{params, Connection, TransactionBehavior};
 use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
 use uuid::Uuid;
 
+const DEFAULT_IMAGE_SIZE: &str = "1024x1024";
+const DEFAULT_IMAGE_QUALITY: &str = "standard";
+const DEFAULT_LANGUAGE: &str = "en";
+const MAX_THEME_LEN: usize = 40;
+// Prompts and guesses are sent on to the image and embedding models, so keep
+// them to a sentence.
+const MAX_PROMPT_LEN: usize = 200;
+const MAX_GUESS_LEN: usize = 200;
+
+/// The new game, hosted by its creator, and the code others join it with.
 #[derive(Serialize)]
 struct CreateGameResponse {
     game_code: String,
+    #[serde(flatten)]
+    game_state: GameState,
+}
+
+/// Settings for a new game. Anything left out, or the whole body, falls back
+/// to the server's defaults.
+#[derive(Deserialize, Default)]
+pub struct CreateGameRequest {
+    total_rounds: Option<i32>,
+    max_players: Option<usize>,
+    imagining_seconds: Option<u32>,
+    guessing_seconds: Option<u32>,
+    image_model: Option<String>,
+    image_size: Option<String>,
+    image_quality: Option<String>,
+    scoring: Option<String>,
+    language: Option<String>,
+    theme: Option<String>,
+    visibility: Option<String>,
 }
 
 #[derive(Deserialize)]
 pub struct JoinGameRequest {
     game_code: String,
-    player_id: String,
 }
 
-#[derive(Serialize)]
-struct JoinGameResponse {
+#[derive(Deserialize)]
+pub struct PlayerReadyRequest {
     game_uuid: String,
+    /// Pass `false` to take back being ready.
+    ready: Option<bool>,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct PlayerReadyRequest {
+#[derive(Deserialize)]
+pub struct GameRequest {
     game_uuid: String,
-    player_id: String,
 }
 
 #[derive(Deserialize)]
-pub struct SubmitPromptRequest {
+pub struct KickPlayerRequest {
     game_uuid: String,
     player_id: String,
-    prompt: String,
 }
 
-#[derive(Serialize, Deserialize)]
-struct Player {
-    id: String,
-    username: String,
-    score: i32,
-    ready: bool,
+#[derive(Deserialize)]
+pub struct SubmitPromptRequest {
+    game_uuid: String,
+    prompt: String,
 }
 
 #[derive(Deserialize)]
@@ -48,286 +93,1090 @@ pub struct GetGameStateRequest {
     game_id: String,
 }
 
+/// Messages a player can send over their game socket.
 #[derive(Deserialize)]
-pub struct ScoreGuessPayload {
-    prompt: String,
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    /// The player switched away, e.g. to another browser tab.
+    Away,
+    Back,
+}
+
+#[derive(Deserialize)]
+pub struct SubmitGuessRequest {
+    game_uuid: String,
     guess: String,
 }
 
-pub async fn get_game_state(game_data: web::Json<GetGameStateRequest>) -> impl Responder {
-    let conn = match Connection::open("game_database.db") {
-        Ok(conn) => conn,
+/// The joined game's state. Players who joined without a token were signed
+/// up as guests, and also get the session to authenticate with from now on.
+#[derive(Serialize)]
+struct JoinGameResponse {
+    #[serde(flatten)]
+    game_state: GameState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session: Option<SessionResponse>,
+}
+
+/// Everything a player needs to pick their game back up.
+#[derive(Serialize)]
+struct ActiveGameResponse {
+    game_code: String,
+    you: PlayerView,
+    #[serde(flatten)]
+    game_state: GameState,
+}
+
+#[derive(Serialize)]
+struct SubmitGuessResponse {
+    score: u32,
+    game_state: GameState,
+}
+
+/// Returns the game as the caller may see it; without a token, that's the
+/// spectators' view.
+pub async fn get_game_state(
+    db: web::Data<Database>,
+    user: MaybeAuthenticated,
+    game_data: web::Json<GetGameStateRequest>,
+) -> Result<HttpResponse, AppError> {
+    let game_data = game_data.into_inner();
+    let game_state = db
+        .run(move |conn| load_game_state(conn, &game_data.game_id))
+        .await?;
+    let viewer = user.0.map(|user| user.user_id);
+
+    Ok(HttpResponse::Ok().json(game_state.view_for(viewer.as_deref())))
+}
+
+/// Finds the game the caller is playing in, so a client that lost track of
+/// it (e.g. after a page reload) can resume.
+pub async fn my_active_game(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let response = db
+        .run(move |conn| {
+            let (game_uuid, game_code) = game_store::active_game(conn, &user.user_id)?
+                .ok_or_else(|| AppError::NotFound("You are not in an active game".to_string()))?;
+            let game_state = load_game_state(conn, &game_uuid)?;
+
+            Ok(ActiveGameResponse {
+                game_code,
+                you: game_state.player_view(&user.user_id),
+                game_state: game_state.view_for(Some(&user.user_id)),
+            })
+        })
+        .await?;
+
+    Ok(HttpResponse::Ok().json(response))
+}
+
+pub async fn submit_prompt(
+    db: web::Data<Database>,
+    registry: web::Data<ProviderRegistry>,
+    events: web::Data<GameEvents>,
+    user: AuthenticatedUser,
+    game_data: web::Json<SubmitPromptRequest>,
+) -> Result<HttpResponse, AppError> {
+    let game_data = game_data.into_inner();
+    let prompt = player_text("prompt", "Prompt", &game_data.prompt, MAX_PROMPT_LEN)?;
+    let player_id = user.user_id.clone();
+    let publisher = events.clone();
+
+    let (game_state, drawn_prompt) = db
+        .run(move |conn| {
+            let (before, game_state, drawn_prompt) =
+                update_game(conn, &game_data.game_uuid, |game_state| {
+                    game_state.ensure_allows(GameAction::SubmitPrompt)?;
+                    ensure_player(game_state, &player_id)?;
+
+                    if game_state.has_submitted_prompt(&player_id) {
+                        return Err(AppError::Conflict(
+                            "Player has already submitted a prompt this round".to_string(),
+                        ));
+                    }
+
+                    Ok(game_state.record_prompt(&player_id, &prompt))
+                })?;
+
+            publisher.publish(
+                before,
+                &game_state,
+                Some(GameEvent::PromptSubmitted { player_id }),
+            );
+
+            Ok((game_state, drawn_prompt))
+        })
+        .await?;
+
+    if let Some(prompt) = drawn_prompt {
+        spawn_round_image(db, registry, events, &game_state, prompt);
+    }
+
+    Ok(HttpResponse::Ok().json(game_state.view_for(Some(&user.user_id))))
+}
+
+// How long a round image may take, retries included. Cut off shortly before
+// the generating phase runs out, so a late image never lands after the timer
+// has already moved on to another prompt.
+const ROUND_IMAGE_BUDGET: Duration = Duration::from_secs(GENERATING_SECONDS - 10);
+
+pub(crate) fn spawn_round_image(
+    db: web::Data<Database>,
+    registry: web::Data<ProviderRegistry>,
+    events: web::Data<GameEvents>,
+    game_state: &GameState,
+    prompt: String,
+) {
+    actix_web::rt::spawn(generate_round_image(
+        db,
+        registry,
+        events,
+        game_state.game_id.clone(),
+        game_state.current_round,
+        game_state.settings.clone(),
+        prompt,
+    ));
+}
+
+/// Illustrates the drawn prompt in the background and opens guessing once the
+/// image is ready. If generation fails, another submitted prompt is drawn;
+/// when none are left the round goes back to collecting prompts.
+async fn generate_round_image(
+    db: web::Data<Database>,
+    registry: web::Data<ProviderRegistry>,
+    events: web::Data<GameEvents>,
+    game_uuid: String,
+    round: i32,
+    settings: GameSettings,
+    mut prompt: String,
+) {
+    loop {
+        let payload = ImageRequestPayload {
+            model: settings.image_model.clone(),
+            prompt: prompt.clone(),
+            size: settings.image_size.clone(),
+            quality: settings.image_quality.clone(),
+            n: 1,
+        };
+
+        let image = match registry.for_model(&payload.model) {
+            Some(provider) => tokio::time::timeout(ROUND_IMAGE_BUDGET, provider.image(&payload))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(AppError::UpstreamUnavailable(
+                        "Image generation timed out".to_string(),
+                    ))
+                }),
+            None => Err(AppError::BadRequest(format!(
+                "No AI provider available for model {}",
+                payload.model
+            ))),
+        };
+
+        let outcome = db
+            .run({
+                let events = events.clone();
+                let game_uuid = game_uuid.clone();
+                let prompt = prompt.clone();
+                move |conn| apply_round_image(conn, &events, &game_uuid, round, &prompt, image)
+            })
+            .await;
+
+        match outcome {
+            Ok(Some(next_prompt)) => prompt = next_prompt,
+            Ok(None) => return,
+            Err(e) => {
+                eprintln!("Error updating game {}: {}", game_uuid, e);
+                return;
+            }
+        }
+    }
+}
+
+/// Stores the outcome of an image generation attempt. Returns the next prompt
+/// to try if the attempt failed and another prompt was drawn.
+fn apply_round_image(
+    conn: &Connection,
+    events: &GameEvents,
+    game_uuid: &str,
+    round: i32,
+    prompt: &str,
+    image: Result<String, AppError>,
+) -> Result<Option<String>, AppError> {
+    if let Err(e) = &image {
+        eprintln!("Error generating image for game {}: {}", game_uuid, e);
+    }
+
+    let (before, game_state, (next_prompt, event)) = update_game(conn, game_uuid, |game_state| {
+        // The game may have moved on while we waited, or given up on this
+        // prompt after it took too long.
+        if game_state.status != GamePhase::Generating
+            || game_state.current_round != round
+            || game_state.current_prompt != prompt
+        {
+            return Ok((None, None));
+        }
+
+        Ok(match &image {
+            Ok(image) => {
+                game_state.image_ready(image.clone());
+                (None, Some(GameEvent::ImageReady { round }))
+            }
+            Err(_) => (game_state.image_failed(), None),
+        })
+    })?;
+
+    events.publish(before, &game_state, event);
+
+    Ok(next_prompt)
+}
+
+pub async fn submit_guess(
+    db: web::Data<Database>,
+    registry: web::Data<ProviderRegistry>,
+    config: web::Data<Config>,
+    events: web::Data<GameEvents>,
+    user: AuthenticatedUser,
+    game_data: web::Json<SubmitGuessRequest>,
+) -> Result<HttpResponse, AppError> {
+    let game_uuid = game_data.game_uuid.clone();
+    let guess = player_text("guess", "Guess", &game_data.guess, MAX_GUESS_LEN)?;
+    let player_id = user.user_id.clone();
+
+    let game_state = db
+        .run({
+            let game_uuid = game_uuid.clone();
+            let player_id = player_id.clone();
+            move |conn| {
+                load_game_state(conn, &game_uuid)
+                    .and_then(|game_state| check_guess(&game_state, &player_id).map(|_| game_state))
+            }
+        })
+        .await?;
+    let round = game_state.current_round;
+
+    // The prompt never leaves the server; the guess is scored against the
+    // copy stored with the game.
+    let similarity = ai_handlers::calculate_similarity(
+        &registry,
+        &config.models.embedding,
+        config.models.embedding_baseline,
+        &game_state.current_prompt,
+        &guess,
+    )
+    .await?;
+    let score = game_state.settings.scoring.points(similarity);
+
+    // Scoring takes a while, so check the guess still counts against the
+    // game as it is now.
+    let game_state = db
+        .run(move |conn| {
+            let (before, game_state, ()) = update_game(conn, &game_uuid, |game_state| {
+                check_guess(game_state, &player_id)?;
+
+                if game_state.current_round != round {
+                    return Err(AppError::Conflict(
+                        "The round ended before the guess was scored".to_string(),
+                    ));
+                }
+
+                game_state.record_guess(&player_id, &guess, score as i32);
+                Ok(())
+            })?;
+
+            events.publish(
+                before,
+                &game_state,
+                Some(GameEvent::GuessScored { player_id, score }),
+            );
+
+            Ok(game_state)
+        })
+        .await?;
+
+    Ok(HttpResponse::Ok().json(SubmitGuessResponse {
+        score,
+        game_state: game_state.view_for(Some(&user.user_id)),
+    }))
+}
+
+fn check_guess(game_state: &GameState, player_id: &str) -> Result<(), AppError> {
+    game_state.ensure_allows(GameAction::SubmitGuess)?;
+    ensure_player(game_state, player_id)?;
+
+    if player_id == game_state.current_prompt_author {
+        return Err(AppError::Conflict(
+            "Players cannot guess their own prompt".to_string(),
+        ));
+    }
+
+    if game_state.has_guessed(player_id) {
+        return Err(AppError::Conflict(
+            "Player has already guessed this round".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Pushes game events to the client over a WebSocket. The first message is
+/// a `snapshot` of the current state; later messages are sent as handlers
+/// change the game. The state is shown as the token's user may see it, or as
+/// spectators do without one. Players who connect with their token are shown
+/// as connected for as long as they have a socket open.
+pub async fn game_socket(
+    req: HttpRequest,
+    body: web::Payload,
+    game_uuid: web::Path<String>,
+    db: web::Data<Database>,
+    events: web::Data<GameEvents>,
+    user: SocketUser,
+) -> Result<HttpResponse, AppError> {
+    let game_uuid = game_uuid.into_inner();
+    // Subscribed before the snapshot is read, so nothing published in between
+    // is missed. Updates the snapshot already includes are skipped later.
+    let updates = events.subscribe(&game_uuid);
+    let game_state = match db
+        .run({
+            let game_uuid = game_uuid.clone();
+            move |conn| load_game_state(conn, &game_uuid)
+        })
+        .await
+    {
+        Ok(game_state) => game_state,
         Err(e) => {
-            eprintln!("Error connecting to database: {}", e);
-            return HttpResponse::InternalServerError().finish();
+            drop(updates);
+            events.release(&game_uuid);
+            return Err(e);
         }
     };
+    let viewer = user.0.map(|user| user.user_id);
 
-    let game_state: GameState = match conn.query_row(
-        "SELECT state FROM games WHERE uuid = ?1",
-        params![game_data.game_id],
-        |row| {
-            let state_json: String = row.get(0)?;
-            serde_json::from_str(&state_json).map_err(|_| rusqlite::Error::InvalidQuery)
-        },
-    ) {
-        Ok(state) => state,
-        Err(_) => return HttpResponse::NotFound().finish(),
-    };
+    let (response, session, messages) = actix_ws::handle(&req, body)
+        .map_err(|e| AppError::BadRequest(format!("WebSocket handshake failed: {}", e)))?;
+
+    actix_web::rt::spawn(run_game_socket(
+        db, events, game_state, viewer, updates, session, messages,
+    ));
 
-    HttpResponse::Ok().json(game_state)
+    Ok(response)
 }
 
-pub async fn submit_prompt(game_data: web::Json<SubmitPromptRequest>) -> impl Responder {
-    let conn = match Connection::open("game_database.db") {
-        Ok(conn) => conn,
-        Err(e) => {
-            eprintln!("Error connecting to database: {}", e);
-            return HttpResponse::InternalServerError().finish();
+async fn run_game_socket(
+    db: web::Data<Database>,
+    events: web::Data<GameEvents>,
+    game_state: GameState,
+    viewer: Option<String>,
+    mut updates: broadcast::Receiver<Arc<GameUpdate>>,
+    mut session: actix_ws::Session,
+    mut messages: actix_ws::MessageStream,
+) {
+    let game_uuid = game_state.game_id.clone();
+    let viewer = viewer.as_deref();
+    // Only players' connections count towards their presence. Set once the
+    // viewer is a player, which may only happen after the socket opened.
+    let mut player_id = None;
+    let mut snapshot_version = game_state.version;
+
+    let plays = viewer.filter(|viewer| game_state.is_player(viewer));
+
+    if send_snapshot(&mut session, game_state, viewer)
+        .await
+        .is_err()
+    {
+        return;
+    }
+    track_presence(&db, &events, &game_uuid, plays, &mut player_id).await;
+
+    let close_reason = loop {
+        tokio::select! {
+            update = updates.recv() => {
+                let sent = match update {
+                    // Already part of the snapshot.
+                    Ok(update) if update.state.version <= snapshot_version => Ok(()),
+                    Ok(update) => {
+                        let plays = viewer.filter(|viewer| update.state.is_player(viewer));
+                        track_presence(&db, &events, &game_uuid, plays, &mut player_id).await;
+                        send_update(&mut session, &update, viewer).await
+                    }
+                    // We missed some updates; resync with the latest state.
+                    Err(RecvError::Lagged(_)) => {
+                        let game_uuid = game_uuid.clone();
+                        match db.run(move |conn| load_game_state(conn, &game_uuid)).await {
+                            Ok(game_state) => {
+                                snapshot_version = game_state.version;
+                                let plays = viewer.filter(|viewer| game_state.is_player(viewer));
+                                let game_uuid = &game_state.game_id;
+                                track_presence(&db, &events, game_uuid, plays, &mut player_id)
+                                    .await;
+                                send_snapshot(&mut session, game_state, viewer).await
+                            }
+                            Err(_) => Err(actix_ws::Closed),
+                        }
+                    }
+                    Err(RecvError::Closed) => Err(actix_ws::Closed),
+                };
+
+                if sent.is_err() {
+                    break None;
+                }
+            }
+            message = messages.next() => match message {
+                Some(Ok(Message::Ping(bytes))) => {
+                    if session.pong(&bytes).await.is_err() {
+                        break None;
+                    }
+                }
+                Some(Ok(Message::Text(text))) => {
+                    let presence = match serde_json::from_str(&text) {
+                        Ok(ClientMessage::Away) => Presence::Away,
+                        Ok(ClientMessage::Back) => Presence::Connected,
+                        Err(_) => continue,
+                    };
+                    if let Some(player_id) = &player_id {
+                        update_presence(&db, &events, &game_uuid, player_id, presence).await;
+                    }
+                }
+                Some(Ok(Message::Close(reason))) => break reason,
+                Some(Ok(_)) => {}
+                Some(Err(_)) | None => break None,
+            },
         }
     };
 
-    let mut game_state: GameState = match conn.query_row(
-        "SELECT state FROM games WHERE uuid = ?1",
-        params![game_data.game_uuid],
-        |row| {
-            let state_json: String = row.get(0)?;
-            serde_json::from_str(&state_json).map_err(|_| rusqlite::Error::InvalidQuery)
-        },
-    ) {
-        Ok(state) => state,
-        Err(_) => return HttpResponse::NotFound().finish(),
+    let _ = session.close(close_reason).await;
+
+    if let Some(player_id) = &player_id {
+        if events.disconnect(&game_uuid, player_id) {
+            update_presence(&db, &events, &game_uuid, player_id, Presence::Disconnected).await;
+        }
+    }
+}
+
+/// Starts counting the socket towards its viewer's presence the first time
+/// the game shows them as a player (`plays`).
+async fn track_presence(
+    db: &web::Data<Database>,
+    events: &web::Data<GameEvents>,
+    game_uuid: &str,
+    plays: Option<&str>,
+    player_id: &mut Option<String>,
+) {
+    let (None, Some(viewer)) = (&player_id, plays) else {
+        return;
     };
 
-    if game_state.status != "imagining" {
-        return HttpResponse::BadRequest().body("Game is not in the imagining phase");
+    *player_id = Some(viewer.to_string());
+    if events.connect(game_uuid, viewer) {
+        update_presence(db, events, game_uuid, viewer, Presence::Connected).await;
     }
+}
 
-    let player_id = game_data.player_id.clone();
-    let prompt = game_data.prompt.clone();
+async fn update_presence(
+    db: &web::Data<Database>,
+    events: &web::Data<GameEvents>,
+    game_uuid: &str,
+    player_id: &str,
+    presence: Presence,
+) {
+    let now = unix_now().as_secs();
+    let result = db
+        .run({
+            let events = events.clone();
+            let game_uuid = game_uuid.to_string();
+            let player_id = player_id.to_string();
+            move |conn| {
+                let (before, game_state, changed) = update_game(conn, &game_uuid, |game_state| {
+                    Ok(game_state.set_presence(&player_id, presence, now))
+                })?;
 
-    game_state.submitted_prompts.push((player_id, prompt));
+                if changed {
+                    events.publish(
+                        before,
+                        &game_state,
+                        Some(GameEvent::PresenceChanged {
+                            player_id,
+                            presence,
+                        }),
+                    );
+                }
+                Ok(())
+            }
+        })
+        .await;
 
-    if game_state.submitted_prompts.len() == game_state.players.len() {
-        game_state.status = "guessing".to_string();
+    if let Err(e) = result {
+        eprintln!("Error updating presence in game {}: {}", game_uuid, e);
     }
+}
+
+async fn send_snapshot(
+    session: &mut actix_ws::Session,
+    game_state: GameState,
+    viewer: Option<&str>,
+) -> Result<(), actix_ws::Closed> {
+    let update = GameUpdate {
+        event: GameEvent::Snapshot,
+        state: Arc::new(game_state),
+    };
+
+    send_update(session, &update, viewer).await
+}
+
+/// Sends an update with the state narrowed down to what `viewer` may see.
+async fn send_update(
+    session: &mut actix_ws::Session,
+    update: &GameUpdate,
+    viewer: Option<&str>,
+) -> Result<(), actix_ws::Closed> {
+    let update = GameUpdate {
+        event: update.event.clone(),
+        state: Arc::new(update.state.view_for(viewer)),
+    };
 
-    match conn.execute(
-        "UPDATE games SET state = ?1 WHERE uuid = ?2",
-        params![
-            serde_json::to_string(&game_state).unwrap(),
-            game_data.game_uuid
-        ],
-    ) {
-        Ok(_) => (),
+    match serde_json::to_string(&update) {
+        Ok(json) => session.text(json).await,
         Err(e) => {
-            eprintln!("Error updating game state: {}", e);
-            return HttpResponse::InternalServerError().finish();
+            eprintln!("Error serializing game update: {}", e);
+            Ok(())
         }
     }
+}
 
-    HttpResponse::Ok().json(game_state)
+fn ensure_player(game_state: &GameState, player_id: &str) -> Result<(), AppError> {
+    if game_state.is_player(player_id) {
+        Ok(())
+    } else {
+        Err(AppError::BadRequest(
+            "Player is not in this game".to_string(),
+        ))
+    }
 }
 
-#[derive(Serialize, Deserialize)]
-struct GameState {
-    game_id: String,
-    status: String,
-    current_round: i32,
-    total_rounds: i32,
-    players: Vec<Player>,
-    current_prompt: String,
-    current_image: String,
-    submitted_prompts: Vec<(String, String)>,
-    submitted_guesses: Vec<(String, String, String)>,
+pub async fn create_game(
+    db: web::Data<Database>,
+    registry: web::Data<ProviderRegistry>,
+    config: web::Data<Config>,
+    user: AuthenticatedUser,
+    body: web::Bytes,
+) -> Result<HttpResponse, AppError> {
+    // Read by hand rather than through `web::Json` so a request without a
+    // body, or a content type, gets the defaults.
+    let game_data = if body.trim_ascii().is_empty() {
+        CreateGameRequest::default()
+    } else {
+        serde_json::from_slice(&body)
+            .map_err(|e| AppError::BadRequest(format!("Invalid game settings: {}", e)))?
+    };
+    let game_state = new_game(game_data, &registry, &config)?;
+    let response = db
+        .run(move |conn| insert_game(conn, game_state, &user.user_id))
+        .await?;
+
+    Ok(HttpResponse::Ok().json(response))
 }
 
-pub async fn create_game() -> impl Responder {
-    let mut game_code;
-    let game_uuid = Uuid::new_v4().to_string();
+/// Builds a game from the requested settings and the server's defaults,
+/// rejecting every invalid setting at once.
+fn new_game(
+    request: CreateGameRequest,
+    registry: &ProviderRegistry,
+    config: &Config,
+) -> Result<GameState, AppError> {
+    let mut errors = Vec::new();
 
-    let conn = match Connection::open("game_database.db") {
-        Ok(conn) => conn,
-        Err(e) => {
-            eprintln!("Error connecting to database: {}", e);
-            return HttpResponse::InternalServerError().finish();
+    let total_rounds = request.total_rounds.unwrap_or(config.game.total_rounds);
+    if !(1..=MAX_TOTAL_ROUNDS).contains(&total_rounds) {
+        errors.push(FieldError::new(
+            "total_rounds",
+            "out_of_range",
+            format!("Rounds must be between 1 and {}", MAX_TOTAL_ROUNDS),
+        ));
+    }
+
+    let min_players = config.game.min_players;
+    let max_players = request.max_players.unwrap_or(config.game.max_players);
+    if !(min_players..=MAX_PLAYERS).contains(&max_players) {
+        errors.push(FieldError::new(
+            "max_players",
+            "out_of_range",
+            format!(
+                "Max players must be between {} and {}",
+                min_players, MAX_PLAYERS
+            ),
+        ));
+    }
+
+    let imagining_seconds = request
+        .imagining_seconds
+        .unwrap_or(config.game.imagining_seconds);
+    let guessing_seconds = request
+        .guessing_seconds
+        .unwrap_or(config.game.guessing_seconds);
+    for (field, seconds) in [
+        ("imagining_seconds", imagining_seconds),
+        ("guessing_seconds", guessing_seconds),
+    ] {
+        if !(MIN_PHASE_SECONDS..=MAX_PHASE_SECONDS).contains(&seconds) {
+            errors.push(FieldError::new(
+                field,
+                "out_of_range",
+                format!(
+                    "Time limits must be between {} and {} seconds",
+                    MIN_PHASE_SECONDS, MAX_PHASE_SECONDS
+                ),
+            ));
         }
+    }
+
+    let image_model = request
+        .image_model
+        .unwrap_or_else(|| config.models.image.clone());
+    let model = ImageModel::find(&image_model);
+    if model.is_none() {
+        errors.push(FieldError::new(
+            "image_model",
+            "unsupported",
+            format!("Image model must be one of {}", ImageModel::names()),
+        ));
+    } else if !registry
+        .for_model(&image_model)
+        .is_some_and(|provider| provider.generates_images())
+    {
+        errors.push(FieldError::new(
+            "image_model",
+            "unsupported",
+            format!("No AI provider available for model {}", image_model),
+        ));
+    }
+
+    let image_size = request
+        .image_size
+        .unwrap_or_else(|| DEFAULT_IMAGE_SIZE.to_string());
+    if let Some(model) = model.filter(|model| !model.sizes.contains(&image_size.as_str())) {
+        errors.push(FieldError::new(
+            "image_size",
+            "unsupported",
+            format!(
+                "Image size for {} must be one of {}",
+                model.name,
+                model.sizes.join(", ")
+            ),
+        ));
+    }
+
+    let image_quality = request
+        .image_quality
+        .unwrap_or_else(|| DEFAULT_IMAGE_QUALITY.to_string());
+    if let Some(model) = model.filter(|model| !model.qualities.contains(&image_quality.as_str())) {
+        errors.push(FieldError::new(
+            "image_quality",
+            "unsupported",
+            format!(
+                "Image quality for {} must be one of {}",
+                model.name,
+                model.qualities.join(", ")
+            ),
+        ));
+    }
+
+    let scoring = request.scoring.as_deref().unwrap_or("similarity");
+    let scoring = scoring.parse().unwrap_or_else(|_| {
+        errors.push(FieldError::new(
+            "scoring",
+            "unsupported",
+            "Scoring must be similarity or threshold".to_string(),
+        ));
+        ScoringMode::Similarity
+    });
+
+    let language = request
+        .language
+        .unwrap_or_else(|| DEFAULT_LANGUAGE.to_string());
+    if !is_language_tag(&language) {
+        errors.push(FieldError::new(
+            "language",
+            "invalid_format",
+            "Language must be a code like en or pt-BR".to_string(),
+        ));
+    }
+
+    let theme = request
+        .theme
+        .map(|theme| theme.trim().to_string())
+        .filter(|theme| !theme.is_empty());
+    if theme
+        .as_ref()
+        .is_some_and(|theme| theme.chars().count() > MAX_THEME_LEN)
+    {
+        errors.push(FieldError::new(
+            "theme",
+            "too_long",
+            format!("Theme must be at most {} characters", MAX_THEME_LEN),
+        ));
+    }
+
+    let visibility = request.visibility.as_deref().unwrap_or("private");
+    let visibility = visibility.parse().unwrap_or_else(|_| {
+        errors.push(FieldError::new(
+            "visibility",
+            "unsupported",
+            "Visibility must be private or public".to_string(),
+        ));
+        Visibility::Private
+    });
+
+    if !errors.is_empty() {
+        return Err(AppError::Validation(errors));
+    }
+
+    Ok(GameState::new(
+        Uuid::new_v4().to_string(),
+        total_rounds,
+        min_players,
+        max_players,
+        GameSettings {
+            imagining_seconds,
+            guessing_seconds,
+            image_model,
+            image_size,
+            image_quality,
+            scoring,
+            language,
+            theme,
+            visibility,
+        },
+    ))
+}
+
+/// Accepts a two-letter language code, optionally followed by a region.
+fn is_language_tag(tag: &str) -> bool {
+    let (language, region) = match tag.split_once('-') {
+        Some((language, region)) => (language, Some(region)),
+        None => (tag, None),
     };
 
+    language.len() == 2
+        && language.bytes().all(|b| b.is_ascii_lowercase())
+        && region.is_none_or(|region| {
+            region.len() == 2 && region.bytes().all(|b| b.is_ascii_uppercase())
+        })
+}
+
+/// Stores a new game, with its creator as host, under a fresh join code.
+fn insert_game(
+    conn: &mut Connection,
+    mut game_state: GameState,
+    host_id: &str,
+) -> Result<CreateGameResponse, AppError> {
+    let mut game_code;
+    let game_uuid = game_state.game_id.clone();
+    // Taking the write lock up front means a concurrent create waits for it
+    // instead of failing when this read has to become a write.
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
     loop {
         game_code = generate_game_code();
 
-        let count: i32 = match conn.query_row(
+        let count: i32 = tx.query_row(
             "SELECT COUNT(*) FROM game_codes WHERE code = ?1",
             params![game_code],
             |row| row.get(0),
-        ) {
-            Ok(count) => count,
-            Err(e) => {
-                eprintln!("Error checking game code uniqueness: {}", e);
-                return HttpResponse::InternalServerError().finish();
-            }
-        };
+        )?;
 
         if count == 0 {
             break;
         }
     }
 
-    let initial_state = GameState {
-        game_id: game_uuid.clone(),
-        status: "waiting".to_string(),
-        current_round: 1,
-        total_rounds: 3,
-        players: vec![],
-        current_prompt: "".to_string(),
-        current_image: "".to_string(),
-        submitted_prompts: vec![],
-        submitted_guesses: vec![],
-    };
+    game_state.add_player(Player::new(
+        host_id.to_string(),
+        load_username(&tx, host_id)?,
+    ))?;
 
-    match conn.execute(
+    tx.execute(
         "INSERT INTO game_codes (code, game_uuid) VALUES (?1, ?2)",
         params![game_code, game_uuid],
-    ) {
-        Ok(_) => (),
-        Err(e) => {
-            eprintln!("Error inserting game code: {}", e);
-            return HttpResponse::InternalServerError().finish();
+    )?;
+
+    game_store::insert_game(&tx, &game_state)?;
+    tx.commit()?;
+
+    Ok(CreateGameResponse {
+        game_code,
+        game_state: game_state.view_for(Some(host_id)),
+    })
+}
+
+fn load_username(conn: &Connection, user_id: &str) -> Result<String, AppError> {
+    conn.query_row(
+        "SELECT username FROM users WHERE id = ?1 AND deleted_at IS NULL",
+        params![user_id],
+        |row| row.get(0),
+    )
+    .map_err(|e| match e {
+        rusqlite::Error::QueryReturnedNoRows => AppError::Unauthorized("Unknown user".to_string()),
+        e => e.into(),
+    })
+}
+
+pub async fn join_game(
+    db: web::Data<Database>,
+    events: web::Data<GameEvents>,
+    keys: web::Data<JwtKeys>,
+    user: MaybeAuthenticated,
+    game_data: web::Json<JoinGameRequest>,
+) -> Result<HttpResponse, AppError> {
+    let game_code = game_data.into_inner().game_code;
+    let response = db
+        .run(move |conn| add_player(conn, &events, &keys, user, &game_code))
+        .await?;
+
+    Ok(HttpResponse::Ok().json(response))
+}
+
+fn add_player(
+    conn: &mut Connection,
+    events: &GameEvents,
+    keys: &JwtKeys,
+    user: MaybeAuthenticated,
+    game_code: &str,
+) -> Result<JoinGameResponse, AppError> {
+    let game_uuid: String = conn
+        .query_row(
+            "SELECT game_uuid FROM game_codes WHERE code = ?1",
+            params![game_code],
+            |row| row.get(0),
+        )
+        .map_err(|e| match e {
+            rusqlite::Error::QueryReturnedNoRows => {
+                AppError::NotFound("No game with that code".to_string())
+            }
+            e => e.into(),
+        })?;
+
+    let game_state = load_game_state(conn, &game_uuid)?;
+    if let Some(user) = &user.0 {
+        if game_state.is_player(&user.user_id) {
+            return Ok(JoinGameResponse {
+                game_state: game_state.view_for(Some(&user.user_id)),
+                session: None,
+            });
         }
     }
+    game_state.ensure_allows(GameAction::Join)?;
 
-    match conn.execute(
-        "INSERT INTO games (uuid, state) VALUES (?1, ?2)",
-        params![game_uuid, serde_json::to_string(&initial_state).unwrap()],
-    ) {
-        Ok(_) => (),
-        Err(e) => {
-            eprintln!("Error inserting game: {}", e);
-            return HttpResponse::InternalServerError().finish();
+    // The guest account and the join are committed together, so a join that
+    // fails doesn't leave an orphaned guest behind.
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+    let (player_id, session) = match user.0 {
+        Some(user) => (user.user_id, None),
+        None => {
+            let session = user_handlers::create_guest(&tx, keys)?;
+            (session.user_id.clone(), Some(session))
         }
+    };
+    let player_id = player_id.as_str();
+
+    let username = load_username(&tx, player_id)?;
+
+    let (before, game_state, joined) = update_game(&tx, &game_uuid, |game_state| {
+        game_state.add_player(Player::new(player_id.to_string(), username.clone()))
+    })?;
+    tx.commit()?;
+
+    if joined {
+        events.publish(
+            before,
+            &game_state,
+            Some(GameEvent::PlayerJoined {
+                player_id: player_id.to_string(),
+            }),
+        );
     }
 
-    HttpResponse::Ok().json(CreateGameResponse { game_code })
+    Ok(JoinGameResponse {
+        game_state: game_state.view_for(Some(player_id)),
+        session,
+    })
 }
 
-pub async fn join_game(game_data: web::Json<JoinGameRequest>) -> impl Responder {
-    let conn = match Connection::open("game_database.db") {
-        Ok(conn) => conn,
-        Err(e) => {
-            eprintln!("Error connecting to database: {}", e);
-            return HttpResponse::InternalServerError().finish();
-        }
-    };
+pub async fn player_ready(
+    db: web::Data<Database>,
+    events: web::Data<GameEvents>,
+    user: AuthenticatedUser,
+    game_data: web::Json<PlayerReadyRequest>,
+) -> Result<HttpResponse, AppError> {
+    let PlayerReadyRequest { game_uuid, ready } = game_data.into_inner();
+    let ready = ready.unwrap_or(true);
+    let player_id = user.user_id.clone();
 
-    let game_uuid: String = match conn.query_row(
-        "SELECT game_uuid FROM game_codes WHERE code = ?1",
-        params![game_data.game_code],
-        |row| row.get(0),
-    ) {
-        Ok(uuid) => uuid,
-        Err(e) => {
-            eprintln!("Error retrieving game UUID: {}", e);
-            return HttpResponse::NotFound().finish();
-        }
-    };
+    let game_state = db
+        .run(move |conn| {
+            let (before, game_state, ()) = update_game(conn, &game_uuid, |game_state| {
+                game_state.ensure_allows(GameAction::Ready)?;
+                ensure_player(game_state, &player_id)?;
 
-    let mut game_state: GameState = match conn.query_row(
-        "SELECT state FROM games WHERE uuid = ?1",
-        params![game_uuid],
-        |row| {
-            let state_json: String = row.get(0)?;
-            serde_json::from_str(&state_json).map_err(|_| rusqlite::Error::InvalidQuery)
-        },
-    ) {
-        Ok(state) => state,
-        Err(_) => return HttpResponse::NotFound().finish(),
-    };
+                if let Some(player) = game_state.players.iter_mut().find(|p| p.id == player_id) {
+                    player.ready = ready;
+                }
+                Ok(())
+            })?;
 
-    let username: String = match conn.query_row(
-        "SELECT username FROM users WHERE id = ?1",
-        params![game_data.player_id],
-        |row| row.get(0),
-    ) {
-        Ok(username) => username,
-        Err(_) => "".to_string(),
-    };
+            events.publish(
+                before,
+                &game_state,
+                Some(GameEvent::PlayerReady { player_id, ready }),
+            );
 
-    let player = Player {
-        id: game_data.player_id.clone(),
-        username,
-        score: 0,
-        ready: false,
-    };
+            Ok(game_state)
+        })
+        .await?;
 
-    game_state.players.push(player);
+    Ok(HttpResponse::Ok().json(game_state.view_for(Some(&user.user_id))))
+}
 
-    if game_state.players.len() == 2 && game_state.players.iter().all(|p| p.ready) {
-        game_state.status = "imagining".to_string();
-    }
+/// Lets the host start the first round once everyone is ready.
+pub async fn start_game(
+    db: web::Data<Database>,
+    events: web::Data<GameEvents>,
+    user: AuthenticatedUser,
+    game_data: web::Json<GameRequest>,
+) -> Result<HttpResponse, AppError> {
+    let game_uuid = game_data.into_inner().game_uuid;
+    let player_id = user.user_id.clone();
 
-    match conn.execute(
-        "UPDATE games SET state = ?1 WHERE uuid = ?2",
-        params![serde_json::to_string(&game_state).unwrap(), game_uuid],
-    ) {
-        Ok(_) => (),
-        Err(e) => {
-            eprintln!("Error updating game state: {}", e);
-            return HttpResponse::InternalServerError().finish();
-        }
-    }
+    let game_state = db
+        .run(move |conn| {
+            let (before, game_state, ()) =
+                update_game(conn, &game_uuid, |game_state| game_state.start(&player_id))?;
+
+            events.publish(before, &game_state, None);
+
+            Ok(game_state)
+        })
+        .await?;
 
-    HttpResponse::Ok().json(game_state)
+    Ok(HttpResponse::Ok().json(game_state.view_for(Some(&user.user_id))))
 }
 
-pub async fn player_ready(game_data: web::Json<PlayerReadyRequest>) -> impl Responder {
-    let conn = match Connection::open("game_database.db") {
-        Ok(conn) => conn,
-        Err(e) => {
-            eprintln!("Error connecting to database: {}", e);
-            return HttpResponse::InternalServerError().finish();
-        }
-    };
+pub async fn leave_game(
+    db: web::Data<Database>,
+    registry: web::Data<ProviderRegistry>,
+    events: web::Data<GameEvents>,
+    user: AuthenticatedUser,
+    game_data: web::Json<GameRequest>,
+) -> Result<HttpResponse, AppError> {
+    let game_uuid = game_data.into_inner().game_uuid;
+    let player_id = user.user_id.clone();
+    let publisher = events.clone();
 
-    let mut game_state: GameState = match conn.query_row(
-        "SELECT state FROM games WHERE uuid = ?1",
-        params![game_data.game_uuid],
-        |row| {
-            let state_json: String = row.get(0)?;
-            serde_json::from_str(&state_json).map_err(|_| rusqlite::Error::InvalidQuery)
-        },
-    ) {
-        Ok(state) => state,
-        Err(_) => return HttpResponse::NotFound().finish(),
-    };
+    let (game_state, drawn_prompt) = db
+        .run(move |conn| {
+            let (before, game_state, drawn_prompt) = update_game(conn, &game_uuid, |game_state| {
+                ensure_player(game_state, &player_id)?;
+                game_state.ensure_allows(GameAction::Leave)?;
 
-    if let Some(player) = game_state
-        .players
-        .iter_mut()
-        .find(|p| p.id == game_data.player_id)
-    {
-        player.ready = true;
+                Ok(game_state.remove_player(&player_id))
+            })?;
+
+            publisher.publish(
+                before,
+                &game_state,
+                Some(GameEvent::PlayerLeft { player_id }),
+            );
+
+            Ok((game_state, drawn_prompt))
+        })
+        .await?;
+
+    if let Some(prompt) = drawn_prompt {
+        spawn_round_image(db, registry, events, &game_state, prompt);
     }
 
-    if game_state.players.len() == 2 && game_state.players.iter().all(|p| p.ready) {
-        game_state.status = "imagining".to_string();
+    Ok(HttpResponse::Ok().json(game_state.view_for(Some(&user.user_id))))
+}
+
+/// Lets the host remove a player, who may not join the game again.
+pub async fn kick_player(
+    db: web::Data<Database>,
+    registry: web::Data<ProviderRegistry>,
+    events: web::Data<GameEvents>,
+    user: AuthenticatedUser,
+    game_data: web::Json<KickPlayerRequest>,
+) -> Result<HttpResponse, AppError> {
+    let KickPlayerRequest {
+        game_uuid,
+        player_id,
+    } = game_data.into_inner();
+    let host_id = user.user_id.clone();
+    let publisher = events.clone();
+
+    let (game_state, drawn_prompt) = db
+        .run(move |conn| {
+            let (before, game_state, drawn_prompt) = update_game(conn, &game_uuid, |game_state| {
+                game_state.ensure_allows(GameAction::Kick)?;
+                game_state.ensure_host(&host_id)?;
+
+                if player_id == host_id {
+                    return Err(AppError::BadRequest(
+                        "Use /leave_game to leave your own game".to_string(),
+                    ));
+                }
+                if !game_state.is_player(&player_id) {
+                    return Err(AppError::NotFound("Player is not in this game".to_string()));
+                }
+
+                game_state.kicked.push(player_id.clone());
+                Ok(game_state.remove_player(&player_id))
+            })?;
+
+            publisher.publish(
+                before,
+                &game_state,
+                Some(GameEvent::PlayerKicked { player_id }),
+            );
+
+            Ok((game_state, drawn_prompt))
+        })
+        .await?;
+
+    if let Some(prompt) = drawn_prompt {
+        spawn_round_image(db, registry, events, &game_state, prompt);
     }
 
-    match conn.execute(
-        "UPDATE games SET state = ?1 WHERE uuid = ?2",
-        params![
-            serde_json::to_string(&game_state).unwrap(),
-            game_data.game_uuid
-        ],
-    ) {
-        Ok(_) => (),
-        Err(e) => {
-            eprintln!("Error updating game state: {}", e);
-            return HttpResponse::InternalServerError().finish();
-        }
+    Ok(HttpResponse::Ok().json(game_state.view_for(Some(&user.user_id))))
+}
+
+/// Trims a prompt or guess and checks it is neither empty nor longer than
+/// `max_len` characters.
+fn player_text(
+    field: &'static str,
+    label: &str,
+    text: &str,
+    max_len: usize,
+) -> Result<String, AppError> {
+    let text = text.trim();
+
+    if text.is_empty() {
+        return Err(AppError::Validation(vec![FieldError::new(
+            field,
+            "too_short",
+            format!("{} cannot be empty", label),
+        )]));
+    }
+    if text.chars().count() > max_len {
+        return Err(AppError::Validation(vec![FieldError::new(
+            field,
+            "too_long",
+            format!("{} must be at most {} characters", label, max_len),
+        )]));
     }
 
-    HttpResponse::Ok().json(game_state)
+    Ok(text.to_string())
 }
 
 fn generate_game_code() -> String {
@@ -345,17 +1194,150 @@ fn generate_game_code() -> String {
     game_code
 }
 
-pub async fn score_guess(payload: web::Json<ScoreGuessPayload>) -> HttpResponse {
-    let prompt = payload.prompt.clone();
-    let guess = payload.guess.clone();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ai_handlers::RequestPayload;
+    use crate::providers::{AiProvider, MockProvider, ProviderResult};
+    use async_trait::async_trait;
 
-    // Wrap the prompt and guess values in web::Json
-    let prompt_json = web::Json(prompt);
-    let guess_json = web::Json(guess);
+    /// A chat-only provider that claims every model.
+    struct ChatOnly;
 
-    // Calculate the similarity score between the prompt and guess
-    let score = ai_handlers::calculate_similarity(prompt_json, guess_json).await;
+    #[async_trait]
+    impl AiProvider for ChatOnly {
+        fn name(&self) -> &'static str {
+            "chat-only"
+        }
+
+        fn supports_model(&self, _model: &str) -> bool {
+            true
+        }
 
-    // Return the score as the response
-    HttpResponse::Ok().body(score)
+        async fn chat(&self, _payload: &RequestPayload) -> ProviderResult<String> {
+            Ok(String::new())
+        }
+    }
+
+    fn registry(provider: impl AiProvider + 'static) -> ProviderRegistry {
+        let mut registry = ProviderRegistry::new();
+        registry.register(provider);
+        registry
+    }
+
+    fn image_settings(model: &str, size: &str, quality: &str) -> CreateGameRequest {
+        CreateGameRequest {
+            image_model: Some(model.to_string()),
+            image_size: Some(size.to_string()),
+            image_quality: Some(quality.to_string()),
+            ..CreateGameRequest::default()
+        }
+    }
+
+    fn rejected_fields(result: Result<GameState, AppError>) -> Vec<&'static str> {
+        match result {
+            Err(AppError::Validation(errors)) => errors.iter().map(|e| e.field).collect(),
+            Err(e) => panic!("expected a validation error, got {}", e),
+            Ok(_) => panic!("expected a validation error, got a game"),
+        }
+    }
+
+    #[test]
+    fn prompts_and_guesses_are_trimmed_and_capped() {
+        assert_eq!(
+            player_text("prompt", "Prompt", "  a cat  ", MAX_PROMPT_LEN).unwrap(),
+            "a cat"
+        );
+
+        let longest = "é".repeat(MAX_GUESS_LEN);
+        assert_eq!(
+            player_text("guess", "Guess", &format!(" {} ", longest), MAX_GUESS_LEN).unwrap(),
+            longest
+        );
+
+        for (text, code) in [
+            ("   ", "too_short"),
+            (&"a".repeat(MAX_GUESS_LEN + 1), "too_long"),
+        ] {
+            match player_text("guess", "Guess", text, MAX_GUESS_LEN) {
+                Err(AppError::Validation(errors)) => {
+                    assert_eq!(errors.len(), 1);
+                    assert_eq!(errors[0].field, "guess");
+                    assert_eq!(errors[0].code, code);
+                }
+                _ => panic!("expected {} to be rejected", code),
+            }
+        }
+    }
+
+    #[test]
+    fn defaults_make_a_valid_game() {
+        let game = new_game(
+            CreateGameRequest::default(),
+            &registry(MockProvider),
+            &Config::default(),
+        )
+        .unwrap();
+
+        assert_eq!(game.settings.image_model, "dall-e-3");
+        assert_eq!(game.settings.image_size, DEFAULT_IMAGE_SIZE);
+        assert_eq!(game.settings.image_quality, DEFAULT_IMAGE_QUALITY);
+    }
+
+    #[test]
+    fn image_settings_are_checked_against_the_model() {
+        let registry = registry(MockProvider);
+        let config = Config::default();
+
+        for (model, size, quality) in [
+            ("dall-e-2", "256x256", "standard"),
+            ("dall-e-3", "1792x1024", "hd"),
+        ] {
+            let request = image_settings(model, size, quality);
+            assert!(new_game(request, &registry, &config).is_ok());
+        }
+
+        for (model, size, quality, field) in [
+            ("dall-e-3", "256x256", "standard", "image_size"),
+            ("dall-e-3", "512x512", "hd", "image_size"),
+            ("dall-e-2", "1792x1024", "standard", "image_size"),
+            ("dall-e-2", "1024x1024", "hd", "image_quality"),
+        ] {
+            let request = image_settings(model, size, quality);
+            assert_eq!(
+                rejected_fields(new_game(request, &registry, &config)),
+                [field],
+                "{} {} {}",
+                model,
+                size,
+                quality
+            );
+        }
+    }
+
+    #[test]
+    fn models_that_cannot_illustrate_are_rejected() {
+        let config = Config::default();
+
+        for model in ["claude-3-haiku-20240307", "gpt-4o", "not-a-model"] {
+            let request = image_settings(model, "1024x1024", "standard");
+            assert_eq!(
+                rejected_fields(new_game(request, &registry(MockProvider), &config)),
+                ["image_model"],
+                "{}",
+                model
+            );
+        }
+
+        let request = image_settings("dall-e-3", "1024x1024", "standard");
+        assert_eq!(
+            rejected_fields(new_game(request, &registry(ChatOnly), &config)),
+            ["image_model"]
+        );
+        let request = image_settings("dall-e-3", "1024x1024", "standard");
+        assert_eq!(
+            rejected_fields(new_game(request, &ProviderRegistry::new(), &config)),
+            ["image_model"]
+        );
+    }
 }
@@ -0,0 +1,1411 @@
+// game_state.rs
+use crate::auth::unix_now;
+use crate::error::AppError;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// The phase a game is in. Serialized as the lowercase name so it stays
+/// compatible with the plain strings previously stored in `games.state`.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum GamePhase {
+    Waiting,
+    Imagining,
+    Generating,
+    Guessing,
+    Finished,
+}
+
+/// Requests a player can make against a game.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum GameAction {
+    Join,
+    Ready,
+    Start,
+    Leave,
+    Kick,
+    SubmitPrompt,
+    SubmitGuess,
+}
+
+// A round needs an author and at least one guesser, so a game that drops
+// below this many players mid-way ends early.
+const MIN_PLAYERS_TO_CONTINUE: usize = 2;
+
+pub(crate) const MAX_TOTAL_ROUNDS: i32 = 20;
+pub(crate) const MAX_PLAYERS: usize = 16;
+pub(crate) const MIN_PHASE_SECONDS: u32 = 10;
+pub(crate) const MAX_PHASE_SECONDS: u32 = 600;
+
+/// An image model rounds can be illustrated with, and the settings it takes.
+pub(crate) struct ImageModel {
+    pub(crate) name: &'static str,
+    pub(crate) sizes: &'static [&'static str],
+    pub(crate) qualities: &'static [&'static str],
+}
+
+pub(crate) const IMAGE_MODELS: &[ImageModel] = &[
+    ImageModel {
+        name: "dall-e-2",
+        sizes: &["256x256", "512x512", "1024x1024"],
+        qualities: &["standard"],
+    },
+    ImageModel {
+        name: "dall-e-3",
+        sizes: &["1024x1024", "1792x1024", "1024x1792"],
+        qualities: &["standard", "hd"],
+    },
+];
+
+impl ImageModel {
+    pub(crate) fn find(name: &str) -> Option<&'static ImageModel> {
+        IMAGE_MODELS.iter().find(|model| model.name == name)
+    }
+
+    pub(crate) fn names() -> String {
+        IMAGE_MODELS
+            .iter()
+            .map(|model| model.name)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+// In threshold scoring, how similar a guess must be to score at all.
+const THRESHOLD_SIMILARITY: u32 = 70;
+
+// A game that fails to get this many images in a row ends, rather than
+// cycling between prompts and failed images forever.
+const MAX_IMAGE_FAILURES: u32 = 3;
+
+// How long to wait on an image before trying another prompt. Longer than a
+// single image request may take, so a slow image isn't given up on early.
+pub(crate) const GENERATING_SECONDS: u64 = 150;
+
+// Stand-ins for the prompts of players who ran out of time.
+const FALLBACK_PROMPTS: &[&str] = &[
+    "a cat wearing a tiny crown",
+    "a lighthouse on a stormy night",
+    "a robot watering a garden",
+    "a dragon reading a newspaper",
+    "a snowman at the beach",
+    "an astronaut riding a bicycle",
+    "a treehouse in a giant mushroom",
+    "a penguin running a bakery",
+];
+
+impl GamePhase {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            GamePhase::Waiting => "waiting",
+            GamePhase::Imagining => "imagining",
+            GamePhase::Generating => "generating",
+            GamePhase::Guessing => "guessing",
+            GamePhase::Finished => "finished",
+        }
+    }
+
+    fn can_transition_to(self, next: GamePhase) -> bool {
+        use GamePhase::*;
+
+        matches!(
+            (self, next),
+            (Waiting, Imagining)
+                | (Imagining, Generating)
+                | (Generating, Guessing)
+                // Every drawn prompt failed to illustrate; collect new ones.
+                | (Generating, Imagining)
+                | (Guessing, Imagining)
+                | (Guessing, Finished)
+                // Too many players left to carry on.
+                | (Imagining, Finished)
+                | (Generating, Finished)
+        )
+    }
+
+    fn allows(self, action: GameAction) -> bool {
+        use GamePhase::*;
+
+        matches!(
+            (self, action),
+            (Waiting, GameAction::Join)
+                | (Waiting, GameAction::Ready)
+                | (Waiting, GameAction::Start)
+                | (
+                    Waiting | Imagining | Generating | Guessing,
+                    GameAction::Leave
+                )
+                | (
+                    Waiting | Imagining | Generating | Guessing,
+                    GameAction::Kick
+                )
+                | (Imagining, GameAction::SubmitPrompt)
+                | (Guessing, GameAction::SubmitGuess)
+        )
+    }
+}
+
+impl FromStr for GamePhase {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "waiting" => Ok(GamePhase::Waiting),
+            "imagining" => Ok(GamePhase::Imagining),
+            "generating" => Ok(GamePhase::Generating),
+            "guessing" => Ok(GamePhase::Guessing),
+            "finished" => Ok(GamePhase::Finished),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for GamePhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl GameAction {
+    fn describe(self) -> &'static str {
+        match self {
+            GameAction::Join => "join",
+            GameAction::Ready => "ready up",
+            GameAction::Start => "start the game",
+            GameAction::Leave => "leave",
+            GameAction::Kick => "kick a player",
+            GameAction::SubmitPrompt => "submit a prompt",
+            GameAction::SubmitGuess => "submit a guess",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub(crate) struct Player {
+    pub(crate) id: String,
+    pub(crate) username: String,
+    pub(crate) score: i32,
+    pub(crate) ready: bool,
+    /// Ran out of time in the last phase they had to act in. Cleared once
+    /// they submit again.
+    #[serde(default)]
+    pub(crate) afk: bool,
+    /// When the player's live connection to the game opened, or `None` if
+    /// they have none open.
+    #[serde(default)]
+    pub(crate) connected_at: Option<u64>,
+    /// When the connected player said they had stepped away.
+    #[serde(default)]
+    pub(crate) away_at: Option<u64>,
+    /// When the player's last connection closed. Players who stay
+    /// disconnected for too long are removed from the game.
+    #[serde(default)]
+    pub(crate) disconnected_at: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Presence {
+    Connected,
+    Away,
+    Disconnected,
+}
+
+impl Player {
+    pub(crate) fn new(id: String, username: String) -> Self {
+        Player {
+            id,
+            username,
+            score: 0,
+            ready: false,
+            afk: false,
+            connected_at: None,
+            away_at: None,
+            disconnected_at: None,
+        }
+    }
+
+    fn set_presence(&mut self, presence: Presence, now: u64) {
+        match presence {
+            Presence::Connected => {
+                self.connected_at = self.connected_at.or(Some(now));
+                self.away_at = None;
+                self.disconnected_at = None;
+            }
+            Presence::Away => {
+                self.connected_at = self.connected_at.or(Some(now));
+                self.away_at = self.away_at.or(Some(now));
+                self.disconnected_at = None;
+            }
+            Presence::Disconnected => {
+                self.connected_at = None;
+                self.away_at = None;
+                self.disconnected_at = self.disconnected_at.or(Some(now));
+            }
+        }
+    }
+}
+
+/// What a player needs, on top of the game state, to pick up a game where
+/// they left off.
+#[derive(Serialize)]
+pub(crate) struct PlayerView {
+    pub(crate) player_id: String,
+    pub(crate) is_host: bool,
+    /// The prompt they submitted this round, if any.
+    pub(crate) submitted_prompt: Option<String>,
+    pub(crate) has_guessed: bool,
+    /// Whether the game is waiting on them: to ready up, submit a prompt or
+    /// guess.
+    pub(crate) needs_to_act: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub(crate) struct ScoredGuess {
+    pub(crate) player_id: String,
+    pub(crate) guess: String,
+    pub(crate) score: i32,
+}
+
+/// The outcome of a finished round, kept so clients can show a recap.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub(crate) struct RoundResult {
+    pub(crate) round: i32,
+    pub(crate) author_id: String,
+    pub(crate) prompt: String,
+    pub(crate) image: String,
+    pub(crate) guesses: Vec<ScoredGuess>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub(crate) struct Standing {
+    pub(crate) rank: usize,
+    pub(crate) player_id: String,
+    pub(crate) username: String,
+    pub(crate) score: i32,
+}
+
+/// How a guess's similarity to the prompt (0-100) turns into points.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ScoringMode {
+    /// The similarity itself.
+    Similarity,
+    /// 100 points for a guess that is close enough, nothing otherwise.
+    Threshold,
+}
+
+impl ScoringMode {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            ScoringMode::Similarity => "similarity",
+            ScoringMode::Threshold => "threshold",
+        }
+    }
+
+    pub(crate) fn points(self, similarity: u32) -> u32 {
+        match self {
+            ScoringMode::Similarity => similarity,
+            ScoringMode::Threshold if similarity >= THRESHOLD_SIMILARITY => 100,
+            ScoringMode::Threshold => 0,
+        }
+    }
+}
+
+impl FromStr for ScoringMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "similarity" => Ok(ScoringMode::Similarity),
+            "threshold" => Ok(ScoringMode::Threshold),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Visibility {
+    /// Only players with the game code can find it.
+    Private,
+    /// Meant to be listed for anyone to join.
+    Public,
+}
+
+impl Visibility {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Visibility::Private => "private",
+            Visibility::Public => "public",
+        }
+    }
+}
+
+impl FromStr for Visibility {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "private" => Ok(Visibility::Private),
+            "public" => Ok(Visibility::Public),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Rules chosen when the game is created, fixed from then on.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub(crate) struct GameSettings {
+    /// Time each player has to submit a prompt.
+    pub(crate) imagining_seconds: u32,
+    /// Time each player has to submit a guess once the image is shown.
+    pub(crate) guessing_seconds: u32,
+    pub(crate) image_model: String,
+    pub(crate) image_size: String,
+    pub(crate) image_quality: String,
+    pub(crate) scoring: ScoringMode,
+    /// The language prompts are written in, e.g. `en` or `pt-BR`.
+    pub(crate) language: String,
+    /// What prompts should be about, if the host picked a theme.
+    pub(crate) theme: Option<String>,
+    pub(crate) visibility: Visibility,
+}
+
+/// Players gather in `Waiting` until the host starts the game. A round then
+/// runs `Imagining` (everyone submits a prompt) -> `Generating` (one prompt is
+/// drawn and illustrated) -> `Guessing` (everyone but the author guesses the
+/// prompt). The game is `Finished` after `total_rounds` rounds.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub(crate) struct GameState {
+    pub(crate) game_id: String,
+    pub(crate) status: GamePhase,
+    /// When the current phase runs out of time, in seconds since the Unix
+    /// epoch. Only set while players (or the image) are being waited on.
+    pub(crate) phase_deadline: Option<u64>,
+    pub(crate) current_round: i32,
+    pub(crate) total_rounds: i32,
+    /// The player who can start the game and kick others. Passes to the
+    /// longest-standing player when the host leaves; empty once everyone has.
+    pub(crate) host_id: String,
+    pub(crate) min_players: usize,
+    pub(crate) max_players: usize,
+    pub(crate) settings: GameSettings,
+    pub(crate) players: Vec<Player>,
+    /// Players the host kicked, who may not join again.
+    #[serde(skip)]
+    pub(crate) kicked: Vec<String>,
+    pub(crate) current_prompt: String,
+    #[serde(default)]
+    pub(crate) current_prompt_author: String,
+    pub(crate) current_image: String,
+    pub(crate) submitted_prompts: Vec<(String, String)>,
+    /// `(player_id, guess, score)` for the current round.
+    pub(crate) submitted_guesses: Vec<(String, String, String)>,
+    #[serde(default)]
+    pub(crate) round_results: Vec<RoundResult>,
+    #[serde(default)]
+    pub(crate) final_standings: Vec<Standing>,
+    /// Images in a row that failed to generate, across prompts and rounds.
+    #[serde(skip)]
+    pub(crate) image_failures: u32,
+    /// The stored version this state was loaded at or saved as. It grows with
+    /// every save, so it orders the states published for a game.
+    #[serde(skip)]
+    pub(crate) version: i64,
+}
+
+impl GameState {
+    pub(crate) fn new(
+        game_id: String,
+        total_rounds: i32,
+        min_players: usize,
+        max_players: usize,
+        settings: GameSettings,
+    ) -> Self {
+        GameState {
+            game_id,
+            status: GamePhase::Waiting,
+            phase_deadline: None,
+            current_round: 1,
+            total_rounds,
+            host_id: "".to_string(),
+            min_players,
+            max_players,
+            settings,
+            players: vec![],
+            kicked: vec![],
+            current_prompt: "".to_string(),
+            current_prompt_author: "".to_string(),
+            current_image: "".to_string(),
+            submitted_prompts: vec![],
+            submitted_guesses: vec![],
+            round_results: vec![],
+            final_standings: vec![],
+            image_failures: 0,
+            version: 0,
+        }
+    }
+
+    /// Rejects `action` with a 409 unless the current phase allows it.
+    pub(crate) fn ensure_allows(&self, action: GameAction) -> Result<(), AppError> {
+        if self.status.allows(action) {
+            Ok(())
+        } else {
+            Err(AppError::Conflict(format!(
+                "Cannot {} while the game is {}",
+                action.describe(),
+                self.status
+            )))
+        }
+    }
+
+    /// Moves to `next`. Phase changes are driven by the game logic below, so
+    /// an illegal transition is a bug rather than a client error.
+    fn transition(&mut self, next: GamePhase) {
+        debug_assert!(
+            self.status.can_transition_to(next),
+            "illegal game transition from {} to {}",
+            self.status,
+            next
+        );
+        self.status = next;
+        self.start_phase_clock();
+    }
+
+    fn start_phase_clock(&mut self) {
+        let seconds = match self.status {
+            GamePhase::Imagining => u64::from(self.settings.imagining_seconds),
+            GamePhase::Generating => GENERATING_SECONDS,
+            GamePhase::Guessing => u64::from(self.settings.guessing_seconds),
+            GamePhase::Waiting | GamePhase::Finished => {
+                self.phase_deadline = None;
+                return;
+            }
+        };
+
+        self.phase_deadline = Some(unix_now().as_secs() + seconds);
+    }
+
+    pub(crate) fn deadline_passed(&self, now: u64) -> bool {
+        self.phase_deadline.is_some_and(|deadline| deadline <= now)
+    }
+
+    /// Ends the current phase once it has run out of time. Players who
+    /// didn't act are marked AFK and returned, with their prompt filled in
+    /// from a stock list or their guess counted as a blank worth nothing. If
+    /// this draws a prompt to illustrate, it is returned too.
+    pub(crate) fn expire_phase(&mut self) -> (Vec<String>, Option<String>) {
+        let mut rng = rand::thread_rng();
+        let mut afk = Vec::new();
+
+        let prompt = match self.status {
+            GamePhase::Imagining => {
+                for player in &mut self.players {
+                    if !self
+                        .submitted_prompts
+                        .iter()
+                        .any(|(author, _)| author == &player.id)
+                    {
+                        let prompt = FALLBACK_PROMPTS.choose(&mut rng).unwrap();
+                        self.submitted_prompts
+                            .push((player.id.clone(), prompt.to_string()));
+                        player.afk = true;
+                        afk.push(player.id.clone());
+                    }
+                }
+                self.draw_prompt_if_complete()
+            }
+            // The image never arrived; try another prompt.
+            GamePhase::Generating => self.image_failed(),
+            GamePhase::Guessing => {
+                for player in &mut self.players {
+                    let guessed = self
+                        .submitted_guesses
+                        .iter()
+                        .any(|(guesser, _, _)| guesser == &player.id);
+                    if player.id != self.current_prompt_author && !guessed {
+                        self.submitted_guesses.push((
+                            player.id.clone(),
+                            "".to_string(),
+                            "0".to_string(),
+                        ));
+                        player.afk = true;
+                        afk.push(player.id.clone());
+                    }
+                }
+                self.finish_round_if_complete();
+                None
+            }
+            GamePhase::Waiting | GamePhase::Finished => None,
+        };
+
+        (afk, prompt)
+    }
+
+    pub(crate) fn is_player(&self, player_id: &str) -> bool {
+        self.players.iter().any(|p| p.id == player_id)
+    }
+
+    /// Adds a player, making them host if the game has none. Returns false
+    /// if they were already in the game, so joining twice is harmless.
+    pub(crate) fn add_player(&mut self, player: Player) -> Result<bool, AppError> {
+        if self.is_player(&player.id) {
+            return Ok(false);
+        }
+
+        self.ensure_allows(GameAction::Join)?;
+
+        if self.kicked.contains(&player.id) {
+            return Err(AppError::Forbidden(
+                "You were removed from this game".to_string(),
+            ));
+        }
+        if self.players.len() >= self.max_players {
+            return Err(AppError::Conflict(format!(
+                "Game is full ({} players)",
+                self.max_players
+            )));
+        }
+
+        if self.host_id.is_empty() {
+            self.host_id = player.id.clone();
+        }
+        self.players.push(player);
+
+        Ok(true)
+    }
+
+    pub(crate) fn ensure_host(&self, player_id: &str) -> Result<(), AppError> {
+        if self.host_id == player_id {
+            Ok(())
+        } else {
+            Err(AppError::Forbidden("Only the host can do that".to_string()))
+        }
+    }
+
+    /// Starts the first round, on the host's say-so, once enough players have
+    /// joined and all of them are ready.
+    pub(crate) fn start(&mut self, player_id: &str) -> Result<(), AppError> {
+        self.ensure_allows(GameAction::Start)?;
+        self.ensure_host(player_id)?;
+
+        if self.players.len() < self.min_players {
+            return Err(AppError::Conflict(format!(
+                "At least {} players are needed to start",
+                self.min_players
+            )));
+        }
+        if !self.players.iter().all(|p| p.ready) {
+            return Err(AppError::Conflict("Not every player is ready".to_string()));
+        }
+
+        self.transition(GamePhase::Imagining);
+        Ok(())
+    }
+
+    /// Takes a player out of the game, handing the host role on if needed.
+    /// Their part in the current round is dropped, which may complete it: if
+    /// that draws a prompt to illustrate, it is returned.
+    pub(crate) fn remove_player(&mut self, player_id: &str) -> Option<String> {
+        let index = self.players.iter().position(|p| p.id == player_id)?;
+        self.players.remove(index);
+
+        if self.host_id == player_id {
+            self.host_id = self
+                .players
+                .first()
+                .map(|p| p.id.clone())
+                .unwrap_or_default();
+        }
+
+        match self.status {
+            GamePhase::Waiting | GamePhase::Finished => None,
+            _ if self.players.len() < MIN_PLAYERS_TO_CONTINUE => {
+                self.transition(GamePhase::Finished);
+                self.final_standings = self.standings();
+                None
+            }
+            GamePhase::Imagining => {
+                self.submitted_prompts
+                    .retain(|(author, _)| author != player_id);
+                self.draw_prompt_if_complete()
+            }
+            GamePhase::Generating => {
+                // The prompt being illustrated stays, even if its author left.
+                if self.current_prompt_author != player_id {
+                    self.submitted_prompts
+                        .retain(|(author, _)| author != player_id);
+                }
+                None
+            }
+            GamePhase::Guessing => {
+                self.finish_round_if_complete();
+                None
+            }
+        }
+    }
+
+    /// The game as `viewer` may see it, with this round's secrets blanked
+    /// out. Players see their own prompt and guess, and the author of the
+    /// drawn prompt sees it and every guess. Everyone else, including
+    /// spectators (`None`), sees the rest once the round ends and moves to
+    /// `round_results`.
+    pub(crate) fn view_for(&self, viewer: Option<&str>) -> GameState {
+        let mut view = self.clone();
+        let is_viewer = |player_id: &str| viewer == Some(player_id);
+        let is_author =
+            !self.current_prompt_author.is_empty() && is_viewer(&self.current_prompt_author);
+
+        if !is_author {
+            view.current_prompt.clear();
+            for (player_id, guess, _) in &mut view.submitted_guesses {
+                if !is_viewer(player_id) {
+                    guess.clear();
+                }
+            }
+        }
+        for (author, prompt) in &mut view.submitted_prompts {
+            if !is_viewer(author) {
+                prompt.clear();
+            }
+        }
+
+        view
+    }
+
+    /// Records a player connecting, stepping away or disconnecting. Returns
+    /// false if that changes nothing, e.g. for a second browser tab.
+    pub(crate) fn set_presence(&mut self, player_id: &str, presence: Presence, now: u64) -> bool {
+        let Some(player) = self.players.iter_mut().find(|p| p.id == player_id) else {
+            return false;
+        };
+
+        let before = player.clone();
+        player.set_presence(presence, now);
+        *player != before
+    }
+
+    /// Players whose connection closed at or before `cutoff` and who haven't
+    /// come back.
+    pub(crate) fn disconnected_since(&self, cutoff: u64) -> Vec<String> {
+        self.players
+            .iter()
+            .filter(|p| p.disconnected_at.is_some_and(|at| at <= cutoff))
+            .map(|p| p.id.clone())
+            .collect()
+    }
+
+    pub(crate) fn player_view(&self, player_id: &str) -> PlayerView {
+        let submitted_prompt = self
+            .submitted_prompts
+            .iter()
+            .find(|(author, _)| author == player_id)
+            .map(|(_, prompt)| prompt.clone());
+        let has_guessed = self.has_guessed(player_id);
+
+        let needs_to_act = match self.status {
+            GamePhase::Waiting => self.players.iter().any(|p| p.id == player_id && !p.ready),
+            GamePhase::Imagining => submitted_prompt.is_none(),
+            GamePhase::Guessing => self.is_guesser(player_id) && !has_guessed,
+            GamePhase::Generating | GamePhase::Finished => false,
+        };
+
+        PlayerView {
+            player_id: player_id.to_string(),
+            is_host: self.host_id == player_id,
+            submitted_prompt,
+            has_guessed,
+            needs_to_act,
+        }
+    }
+
+    pub(crate) fn has_submitted_prompt(&self, player_id: &str) -> bool {
+        self.submitted_prompts
+            .iter()
+            .any(|(author, _)| author == player_id)
+    }
+
+    /// Records a player's prompt. Once everyone has submitted, draws the
+    /// prompt to illustrate and returns it.
+    pub(crate) fn record_prompt(&mut self, player_id: &str, prompt: &str) -> Option<String> {
+        self.submitted_prompts
+            .push((player_id.to_string(), prompt.to_string()));
+
+        if let Some(player) = self.players.iter_mut().find(|p| p.id == player_id) {
+            player.afk = false;
+        }
+
+        self.draw_prompt_if_complete()
+    }
+
+    fn draw_prompt_if_complete(&mut self) -> Option<String> {
+        if !self.submitted_prompts.is_empty()
+            && self
+                .players
+                .iter()
+                .all(|p| self.has_submitted_prompt(&p.id))
+        {
+            self.draw_prompt()
+        } else {
+            None
+        }
+    }
+
+    /// Draws the prompt to illustrate this round, preferring players whose
+    /// prompts haven't been used in an earlier round. Moves the game into
+    /// `Generating` and returns the chosen prompt, or `None` if no prompts
+    /// are left to draw from.
+    fn draw_prompt(&mut self) -> Option<String> {
+        let fresh: Vec<&(String, String)> = self
+            .submitted_prompts
+            .iter()
+            .filter(|(author, _)| {
+                !self
+                    .round_results
+                    .iter()
+                    .any(|result| &result.author_id == author)
+            })
+            .collect();
+
+        let pool = if fresh.is_empty() {
+            self.submitted_prompts.iter().collect()
+        } else {
+            fresh
+        };
+        let (author, prompt) = pool.choose(&mut rand::thread_rng())?;
+        let (author, prompt) = (author.clone(), prompt.clone());
+
+        self.current_prompt_author = author;
+        self.current_prompt = prompt.clone();
+        if self.status == GamePhase::Generating {
+            // A fresh prompt gets a fresh chance at an image.
+            self.start_phase_clock();
+        } else {
+            self.transition(GamePhase::Generating);
+        }
+
+        Some(prompt)
+    }
+
+    /// Sets the illustration for the drawn prompt and opens guessing.
+    pub(crate) fn image_ready(&mut self, image: String) {
+        self.current_image = image;
+        self.image_failures = 0;
+        self.transition(GamePhase::Guessing);
+    }
+
+    /// Gives up on the drawn prompt after its image failed to generate, and
+    /// draws another one. Returns the new prompt, or `None` once every prompt
+    /// has failed, in which case the round restarts from `Imagining`. After
+    /// `MAX_IMAGE_FAILURES` failures in a row the game is finished instead.
+    pub(crate) fn image_failed(&mut self) -> Option<String> {
+        let author = std::mem::take(&mut self.current_prompt_author);
+        self.submitted_prompts
+            .retain(|(player_id, _)| player_id != &author);
+        self.current_prompt.clear();
+
+        self.image_failures += 1;
+        if self.image_failures >= MAX_IMAGE_FAILURES {
+            self.submitted_prompts.clear();
+            self.transition(GamePhase::Finished);
+            self.final_standings = self.standings();
+            return None;
+        }
+
+        let prompt = self.draw_prompt();
+        if prompt.is_none() {
+            self.transition(GamePhase::Imagining);
+        }
+
+        prompt
+    }
+
+    pub(crate) fn has_guessed(&self, player_id: &str) -> bool {
+        self.submitted_guesses
+            .iter()
+            .any(|(guesser, _, _)| guesser == player_id)
+    }
+
+    fn is_guesser(&self, player_id: &str) -> bool {
+        player_id != self.current_prompt_author && self.is_player(player_id)
+    }
+
+    /// Records a scored guess and adds it to the player's total. Finishes the
+    /// round once every player other than the author has guessed.
+    pub(crate) fn record_guess(&mut self, player_id: &str, guess: &str, score: i32) {
+        self.submitted_guesses
+            .push((player_id.to_string(), guess.to_string(), score.to_string()));
+
+        if let Some(player) = self.players.iter_mut().find(|p| p.id == player_id) {
+            player.score += score;
+            player.afk = false;
+        }
+
+        self.finish_round_if_complete();
+    }
+
+    fn finish_round_if_complete(&mut self) {
+        if self
+            .players
+            .iter()
+            .filter(|p| self.is_guesser(&p.id))
+            .all(|p| self.has_guessed(&p.id))
+        {
+            self.finish_round();
+        }
+    }
+
+    fn finish_round(&mut self) {
+        let guesses = self
+            .submitted_guesses
+            .drain(..)
+            .map(|(player_id, guess, score)| ScoredGuess {
+                player_id,
+                guess,
+                score: score.parse().unwrap_or(0),
+            })
+            .collect();
+
+        self.round_results.push(RoundResult {
+            round: self.current_round,
+            author_id: std::mem::take(&mut self.current_prompt_author),
+            prompt: std::mem::take(&mut self.current_prompt),
+            image: std::mem::take(&mut self.current_image),
+            guesses,
+        });
+        self.submitted_prompts.clear();
+
+        if self.current_round >= self.total_rounds {
+            self.transition(GamePhase::Finished);
+            self.final_standings = self.standings();
+        } else {
+            self.current_round += 1;
+            self.transition(GamePhase::Imagining);
+        }
+    }
+
+    /// Players ordered by score, with tied players sharing a rank.
+    pub(crate) fn standings(&self) -> Vec<Standing> {
+        let mut players: Vec<&Player> = self.players.iter().collect();
+        players.sort_by_key(|player| std::cmp::Reverse(player.score));
+
+        let mut standings: Vec<Standing> = Vec::with_capacity(players.len());
+        for (index, player) in players.into_iter().enumerate() {
+            let rank = match standings.last() {
+                Some(previous) if previous.score == player.score => previous.rank,
+                _ => index + 1,
+            };
+
+            standings.push(Standing {
+                rank,
+                player_id: player.id.clone(),
+                username: player.username.clone(),
+                score: player.score,
+            });
+        }
+
+        standings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> GameSettings {
+        GameSettings {
+            imagining_seconds: 90,
+            guessing_seconds: 60,
+            image_model: "dall-e-3".to_string(),
+            image_size: "1024x1024".to_string(),
+            image_quality: "standard".to_string(),
+            scoring: ScoringMode::Similarity,
+            language: "en".to_string(),
+            theme: None,
+            visibility: Visibility::Private,
+        }
+    }
+
+    fn waiting_game(players: &[&str], total_rounds: i32) -> GameState {
+        let mut state = GameState::new("game".to_string(), total_rounds, 2, 4, settings());
+        for id in players {
+            let player = Player::new(id.to_string(), format!("{}-name", id));
+            assert!(state.add_player(player).unwrap());
+        }
+        state
+    }
+
+    fn started_game(players: &[&str], total_rounds: i32) -> GameState {
+        let mut state = waiting_game(players, total_rounds);
+        for player in &mut state.players {
+            player.ready = true;
+        }
+        state.start(players[0]).unwrap();
+        state
+    }
+
+    /// Submits a prompt for every player, returning the drawn one.
+    fn submit_all_prompts(state: &mut GameState) -> String {
+        let ids: Vec<String> = state.players.iter().map(|p| p.id.clone()).collect();
+        let mut drawn = None;
+        for id in &ids {
+            drawn = state.record_prompt(id, &format!("{} prompt", id));
+        }
+        drawn.expect("the last prompt should draw one")
+    }
+
+    fn guessers(state: &GameState) -> Vec<String> {
+        state
+            .players
+            .iter()
+            .filter(|p| p.id != state.current_prompt_author)
+            .map(|p| p.id.clone())
+            .collect()
+    }
+
+    fn score_of(state: &GameState, id: &str) -> i32 {
+        state.players.iter().find(|p| p.id == id).unwrap().score
+    }
+
+    #[test]
+    fn only_legal_transitions_are_allowed() {
+        use GamePhase::*;
+        let phases = [Waiting, Imagining, Generating, Guessing, Finished];
+        let legal = [
+            (Waiting, Imagining),
+            (Imagining, Generating),
+            (Imagining, Finished),
+            (Generating, Guessing),
+            (Generating, Imagining),
+            (Generating, Finished),
+            (Guessing, Imagining),
+            (Guessing, Finished),
+        ];
+
+        for from in phases {
+            for to in phases {
+                assert_eq!(
+                    from.can_transition_to(to),
+                    legal.contains(&(from, to)),
+                    "{} -> {}",
+                    from,
+                    to
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn actions_are_only_allowed_in_their_phases() {
+        use GamePhase::*;
+        let cases = [
+            (GameAction::Join, vec![Waiting]),
+            (GameAction::Ready, vec![Waiting]),
+            (GameAction::Start, vec![Waiting]),
+            (
+                GameAction::Leave,
+                vec![Waiting, Imagining, Generating, Guessing],
+            ),
+            (
+                GameAction::Kick,
+                vec![Waiting, Imagining, Generating, Guessing],
+            ),
+            (GameAction::SubmitPrompt, vec![Imagining]),
+            (GameAction::SubmitGuess, vec![Guessing]),
+        ];
+
+        for (action, allowed) in cases {
+            for phase in [Waiting, Imagining, Generating, Guessing, Finished] {
+                assert_eq!(
+                    phase.allows(action),
+                    allowed.contains(&phase),
+                    "{:?} while {}",
+                    action,
+                    phase
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn phases_round_trip_through_strings() {
+        use GamePhase::*;
+        for phase in [Waiting, Imagining, Generating, Guessing, Finished] {
+            assert_eq!(phase.as_str().parse::<GamePhase>(), Ok(phase));
+        }
+        assert!("playing".parse::<GamePhase>().is_err());
+    }
+
+    #[test]
+    fn first_player_to_join_becomes_host() {
+        let mut state = waiting_game(&["a", "b"], 3);
+        assert_eq!(state.host_id, "a");
+        assert!(!state
+            .add_player(Player::new("b".to_string(), "b".to_string()))
+            .unwrap());
+        assert_eq!(state.players.len(), 2);
+    }
+
+    #[test]
+    fn full_games_and_kicked_players_cannot_be_joined() {
+        let mut state = waiting_game(&["a", "b", "c", "d"], 3);
+        let late = Player::new("e".to_string(), "e".to_string());
+        assert!(matches!(state.add_player(late), Err(AppError::Conflict(_))));
+
+        state.kicked.push("f".to_string());
+        state.remove_player("d");
+        let kicked = Player::new("f".to_string(), "f".to_string());
+        assert!(matches!(
+            state.add_player(kicked),
+            Err(AppError::Forbidden(_))
+        ));
+    }
+
+    #[test]
+    fn starting_needs_the_host_enough_players_and_everyone_ready() {
+        let mut state = waiting_game(&["a"], 3);
+        state.players[0].ready = true;
+        assert!(matches!(state.start("a"), Err(AppError::Conflict(_))));
+
+        state
+            .add_player(Player::new("b".to_string(), "b".to_string()))
+            .unwrap();
+        assert!(matches!(state.start("a"), Err(AppError::Conflict(_))));
+
+        state.players[1].ready = true;
+        assert!(matches!(state.start("b"), Err(AppError::Forbidden(_))));
+
+        state.start("a").unwrap();
+        assert_eq!(state.status, GamePhase::Imagining);
+        assert!(state.phase_deadline.is_some());
+        assert!(matches!(
+            state.ensure_allows(GameAction::Join),
+            Err(AppError::Conflict(_))
+        ));
+    }
+
+    #[test]
+    fn last_prompt_draws_one_and_starts_generating() {
+        let mut state = started_game(&["a", "b", "c"], 3);
+        assert_eq!(state.record_prompt("a", "a prompt"), None);
+        assert_eq!(state.record_prompt("b", "b prompt"), None);
+        assert_eq!(state.status, GamePhase::Imagining);
+
+        let drawn = state.record_prompt("c", "c prompt").unwrap();
+        assert_eq!(state.status, GamePhase::Generating);
+        assert_eq!(state.current_prompt, drawn);
+        assert_eq!(drawn, format!("{} prompt", state.current_prompt_author));
+    }
+
+    #[test]
+    fn image_opens_guessing() {
+        let mut state = started_game(&["a", "b"], 3);
+        submit_all_prompts(&mut state);
+        state.image_ready("https://example.com/image.png".to_string());
+        assert_eq!(state.status, GamePhase::Guessing);
+        assert_eq!(state.current_image, "https://example.com/image.png");
+    }
+
+    #[test]
+    fn failed_images_draw_other_prompts_until_none_are_left() {
+        let mut state = started_game(&["a", "b"], 3);
+        let first = submit_all_prompts(&mut state);
+
+        let second = state.image_failed().unwrap();
+        assert_ne!(first, second);
+        assert_eq!(state.status, GamePhase::Generating);
+
+        assert_eq!(state.image_failed(), None);
+        assert_eq!(state.status, GamePhase::Imagining);
+        assert!(state.submitted_prompts.is_empty());
+        assert_eq!(state.current_round, 1);
+    }
+
+    #[test]
+    fn a_game_whose_images_keep_failing_finishes() {
+        let mut state = started_game(&["a", "b"], 3);
+        submit_all_prompts(&mut state);
+        state.image_failed().unwrap();
+        assert_eq!(state.image_failed(), None);
+        assert_eq!(state.status, GamePhase::Imagining);
+
+        submit_all_prompts(&mut state);
+        assert_eq!(state.image_failures, MAX_IMAGE_FAILURES - 1);
+        assert_eq!(state.image_failed(), None);
+        assert_eq!(state.status, GamePhase::Finished);
+        assert_eq!(state.phase_deadline, None);
+        assert_eq!(state.final_standings.len(), 2);
+    }
+
+    #[test]
+    fn an_image_that_arrives_resets_the_failure_count() {
+        let mut state = started_game(&["a", "b", "c"], 3);
+        submit_all_prompts(&mut state);
+        state.image_failed().unwrap();
+        state.image_failed().unwrap();
+        state.image_ready("https://example.com/image.png".to_string());
+        assert_eq!(state.image_failures, 0);
+    }
+
+    #[test]
+    fn last_guess_finishes_the_round() {
+        let mut state = started_game(&["a", "b", "c"], 3);
+        let prompt = submit_all_prompts(&mut state);
+        let author = state.current_prompt_author.clone();
+        state.image_ready("image".to_string());
+
+        let guessers = guessers(&state);
+        state.record_guess(&guessers[0], "first", 80);
+        assert_eq!(state.status, GamePhase::Guessing);
+        assert_eq!(score_of(&state, &guessers[0]), 80);
+
+        state.record_guess(&guessers[1], "second", 40);
+        assert_eq!(state.status, GamePhase::Imagining);
+        assert_eq!(state.current_round, 2);
+        assert!(state.submitted_prompts.is_empty());
+        assert!(state.submitted_guesses.is_empty());
+        assert!(state.current_prompt.is_empty());
+
+        let result = &state.round_results[0];
+        assert_eq!(result.round, 1);
+        assert_eq!(result.author_id, author);
+        assert_eq!(result.prompt, prompt);
+        assert_eq!(result.image, "image");
+        assert_eq!(
+            result
+                .guesses
+                .iter()
+                .map(|g| (g.guess.as_str(), g.score))
+                .collect::<Vec<_>>(),
+            [("first", 80), ("second", 40)]
+        );
+    }
+
+    #[test]
+    fn later_rounds_prefer_authors_not_yet_drawn() {
+        let mut state = started_game(&["a", "b"], 2);
+        submit_all_prompts(&mut state);
+        let first_author = state.current_prompt_author.clone();
+        state.image_ready("image".to_string());
+        state.record_guess(&guessers(&state)[0], "guess", 10);
+
+        submit_all_prompts(&mut state);
+        assert_ne!(state.current_prompt_author, first_author);
+    }
+
+    #[test]
+    fn game_finishes_with_standings_after_the_last_round() {
+        let mut state = started_game(&["a", "b"], 1);
+        submit_all_prompts(&mut state);
+        state.image_ready("image".to_string());
+        let guesser = guessers(&state)[0].clone();
+        state.record_guess(&guesser, "guess", 55);
+
+        assert_eq!(state.status, GamePhase::Finished);
+        assert_eq!(state.phase_deadline, None);
+        assert_eq!(state.current_round, 1);
+        assert_eq!(state.final_standings[0].player_id, guesser);
+        assert_eq!(state.final_standings[0].score, 55);
+        assert_eq!(state.final_standings[1].score, 0);
+    }
+
+    #[test]
+    fn tied_players_share_a_rank() {
+        let mut state = waiting_game(&["a", "b", "c", "d"], 3);
+        for (player, score) in state.players.iter_mut().zip([50, 90, 50, 10]) {
+            player.score = score;
+        }
+
+        let standings = state.standings();
+        let standings: Vec<(usize, &str, i32)> = standings
+            .iter()
+            .map(|s| (s.rank, s.player_id.as_str(), s.score))
+            .collect();
+        assert_eq!(
+            standings,
+            [(1, "b", 90), (2, "a", 50), (2, "c", 50), (4, "d", 10)]
+        );
+    }
+
+    #[test]
+    fn deadline_passes_at_the_deadline() {
+        let mut state = waiting_game(&["a", "b"], 3);
+        assert!(!state.deadline_passed(u64::MAX));
+
+        state.phase_deadline = Some(100);
+        assert!(!state.deadline_passed(99));
+        assert!(state.deadline_passed(100));
+    }
+
+    #[test]
+    fn imagining_timeout_fills_in_stock_prompts() {
+        let mut state = started_game(&["a", "b", "c"], 3);
+        state.record_prompt("a", "a prompt");
+
+        let (afk, drawn) = state.expire_phase();
+        assert_eq!(afk, ["b", "c"]);
+        assert_eq!(state.status, GamePhase::Generating);
+        assert_eq!(drawn.as_deref(), Some(state.current_prompt.as_str()));
+        for (author, prompt) in &state.submitted_prompts {
+            if author != "a" {
+                assert!(FALLBACK_PROMPTS.contains(&prompt.as_str()));
+            }
+        }
+        let afk_flags: Vec<bool> = state.players.iter().map(|p| p.afk).collect();
+        assert_eq!(afk_flags, [false, true, true]);
+
+        // Submitting again clears the flag.
+        state.image_ready("image".to_string());
+        let guesser = guessers(&state).into_iter().find(|id| id != "a").unwrap();
+        state.record_guess(&guesser, "guess", 10);
+        assert!(!state.players.iter().find(|p| p.id == guesser).unwrap().afk);
+    }
+
+    #[test]
+    fn generating_timeout_tries_another_prompt() {
+        let mut state = started_game(&["a", "b"], 3);
+        let first = submit_all_prompts(&mut state);
+
+        let (afk, drawn) = state.expire_phase();
+        assert!(afk.is_empty());
+        let second = drawn.unwrap();
+        assert_ne!(first, second);
+        assert_eq!(state.status, GamePhase::Generating);
+
+        let (_, drawn) = state.expire_phase();
+        assert_eq!(drawn, None);
+        assert_eq!(state.status, GamePhase::Imagining);
+    }
+
+    #[test]
+    fn guessing_timeout_scores_missing_guesses_as_blank() {
+        let mut state = started_game(&["a", "b", "c"], 3);
+        submit_all_prompts(&mut state);
+        state.image_ready("image".to_string());
+        let guessers = guessers(&state);
+        state.record_guess(&guessers[0], "guess", 70);
+
+        let (afk, drawn) = state.expire_phase();
+        assert_eq!(afk, [guessers[1].clone()]);
+        assert_eq!(drawn, None);
+        assert_eq!(state.status, GamePhase::Imagining);
+        assert_eq!(state.current_round, 2);
+
+        let late = &state.round_results[0].guesses[1];
+        assert_eq!(
+            (late.player_id.as_str(), late.guess.as_str(), late.score),
+            (guessers[1].as_str(), "", 0)
+        );
+        assert_eq!(score_of(&state, &guessers[1]), 0);
+    }
+
+    #[test]
+    fn timeout_does_nothing_while_waiting_or_finished() {
+        let mut state = waiting_game(&["a", "b"], 3);
+        assert_eq!(state.expire_phase(), (vec![], None));
+        assert_eq!(state.status, GamePhase::Waiting);
+
+        state.status = GamePhase::Finished;
+        assert_eq!(state.expire_phase(), (vec![], None));
+        assert_eq!(state.status, GamePhase::Finished);
+    }
+
+    #[test]
+    fn host_role_passes_on_when_the_host_leaves() {
+        let mut state = waiting_game(&["a", "b", "c"], 3);
+        state.remove_player("a");
+        assert_eq!(state.host_id, "b");
+
+        state.remove_player("b");
+        state.remove_player("c");
+        assert_eq!(state.host_id, "");
+        assert_eq!(state.status, GamePhase::Waiting);
+    }
+
+    #[test]
+    fn leaving_during_imagining_can_complete_the_round() {
+        let mut state = started_game(&["a", "b", "c"], 3);
+        state.record_prompt("a", "a prompt");
+        state.record_prompt("b", "b prompt");
+
+        let drawn = state.remove_player("c");
+        assert!(drawn.is_some());
+        assert_eq!(state.status, GamePhase::Generating);
+    }
+
+    #[test]
+    fn leaving_during_guessing_can_complete_the_round() {
+        let mut state = started_game(&["a", "b", "c"], 3);
+        submit_all_prompts(&mut state);
+        state.image_ready("image".to_string());
+        let guessers = guessers(&state);
+        state.record_guess(&guessers[0], "guess", 30);
+
+        state.remove_player(&guessers[1]);
+        assert_eq!(state.status, GamePhase::Imagining);
+        assert_eq!(state.round_results.len(), 1);
+    }
+
+    #[test]
+    fn dropping_below_the_minimum_ends_the_game_in_any_phase() {
+        let phases: [fn(&mut GameState); 3] = [
+            |_| {},
+            |state| {
+                submit_all_prompts(state);
+            },
+            |state| {
+                submit_all_prompts(state);
+                state.image_ready("image".to_string());
+            },
+        ];
+
+        for reach_phase in phases {
+            let mut state = started_game(&["a", "b"], 3);
+            reach_phase(&mut state);
+            state.players[1].score = 20;
+
+            state.remove_player("a");
+            assert_eq!(state.status, GamePhase::Finished);
+            assert_eq!(state.phase_deadline, None);
+            assert_eq!(state.final_standings.len(), 1);
+            assert_eq!(state.final_standings[0].player_id, "b");
+            assert_eq!(state.final_standings[0].score, 20);
+        }
+    }
+
+    #[test]
+    fn viewers_only_see_their_own_secrets() {
+        let mut state = started_game(&["a", "b", "c"], 3);
+        submit_all_prompts(&mut state);
+        state.image_ready("image".to_string());
+        let author = state.current_prompt_author.clone();
+        let guessers = guessers(&state);
+        state.record_guess(&guessers[0], "guess", 30);
+
+        let spectator = state.view_for(None);
+        assert_eq!(spectator.current_prompt, "");
+        assert!(spectator
+            .submitted_prompts
+            .iter()
+            .all(|(_, p)| p.is_empty()));
+        assert_eq!(spectator.submitted_guesses[0].1, "");
+        assert_eq!(spectator.submitted_guesses[0].2, "30");
+
+        let guesser = state.view_for(Some(&guessers[0]));
+        assert_eq!(guesser.current_prompt, "");
+        assert_eq!(guesser.submitted_guesses[0].1, "guess");
+
+        let other = state.view_for(Some(&guessers[1]));
+        assert_eq!(other.submitted_guesses[0].1, "");
+
+        let author_view = state.view_for(Some(&author));
+        assert_eq!(author_view.current_prompt, state.current_prompt);
+        assert_eq!(author_view.submitted_guesses[0].1, "guess");
+        for (id, prompt) in &author_view.submitted_prompts {
+            assert_eq!(prompt.is_empty(), id != &author);
+        }
+    }
+}
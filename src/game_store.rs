@@ -0,0 +1,800 @@
+// game_store.rs
+// Reads and writes games. A game is spread over `games`, `game_players`,
+// `game_kicks`, `prompts`, `images`, `rounds` and `guesses`; a `GameState` is assembled
+// from those tables and changes to it are written back row by row.
+use crate::auth::unix_now;
+use crate::error::AppError;
+use crate::game_events::Checkpoint;
+use crate::game_state::{GamePhase, GameSettings, GameState, Player, RoundResult, ScoredGuess};
+use crate::user_handlers::DELETED_USERNAME;
+use rusqlite::types::Type;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::str::FromStr;
+
+// How many times to re-read and re-apply a change to a game that other
+// requests keep updating underneath us.
+const MAX_UPDATE_ATTEMPTS: u32 = 5;
+
+pub(crate) fn insert_game(conn: &Connection, game_state: &GameState) -> Result<(), AppError> {
+    let settings = &game_state.settings;
+
+    conn.execute(
+        "INSERT INTO games (uuid, status, current_round, total_rounds, host_id,
+             min_players, max_players, created_at, imagining_seconds, guessing_seconds,
+             image_model, image_size, image_quality, scoring, language, theme, visibility)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)",
+        params![
+            game_state.game_id,
+            game_state.status.as_str(),
+            game_state.current_round,
+            game_state.total_rounds,
+            host_id(game_state),
+            game_state.min_players,
+            game_state.max_players,
+            unix_now().as_secs(),
+            settings.imagining_seconds,
+            settings.guessing_seconds,
+            settings.image_model,
+            settings.image_size,
+            settings.image_quality,
+            settings.scoring.as_str(),
+            settings.language,
+            settings.theme,
+            settings.visibility.as_str()
+        ],
+    )?;
+
+    write_players(conn, game_state)?;
+    write_rounds(conn, game_state)
+}
+
+/// Applies `change` to a stored game and saves the result, as long as nobody
+/// else saved the game in the meantime. If they did, the change is retried on
+/// the fresh state, so `change` may run more than once and must only modify
+/// the game state it is given. Returns the game as it was before the change
+/// that stuck, the updated game, and whatever `change` returned.
+pub(crate) fn update_game<T>(
+    conn: &Connection,
+    game_uuid: &str,
+    mut change: impl FnMut(&mut GameState) -> Result<T, AppError>,
+) -> Result<(Checkpoint, GameState, T), AppError> {
+    for _ in 0..MAX_UPDATE_ATTEMPTS {
+        let original = load_game_state(conn, game_uuid)?;
+        let version = original.version;
+        let before = Checkpoint::of(&original);
+
+        let mut game_state = original.clone();
+        let value = change(&mut game_state)?;
+
+        if game_state == original {
+            return Ok((before, game_state, value));
+        }
+        if save_game(conn, &game_state, version)? {
+            game_state.version = version + 1;
+            return Ok((before, game_state, value));
+        }
+    }
+
+    Err(AppError::Conflict(
+        "The game is changing too quickly; please try again".to_string(),
+    ))
+}
+
+/// Finishes a game outright, without loading or checking its current round.
+/// This is how the server gives up on a game it can't move along.
+pub(crate) fn finish_game(conn: &Connection, game_uuid: &str) -> Result<(), AppError> {
+    conn.execute(
+        "UPDATE games SET status = 'finished', phase_deadline = NULL, version = version + 1
+         WHERE uuid = ?1",
+        params![game_uuid],
+    )?;
+
+    Ok(())
+}
+
+/// Games whose current phase ran out of time at or before `now`.
+pub(crate) fn expired_games(conn: &Connection, now: u64) -> Result<Vec<String>, AppError> {
+    let mut stmt = conn.prepare("SELECT uuid FROM games WHERE phase_deadline <= ?1")?;
+    let games = stmt
+        .query_map(params![now], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+
+    Ok(games)
+}
+
+/// Unfinished games with a player whose connection closed at or before
+/// `cutoff`.
+pub(crate) fn games_with_disconnected_players(
+    conn: &Connection,
+    cutoff: u64,
+) -> Result<Vec<String>, AppError> {
+    let mut stmt = conn.prepare(
+        "SELECT DISTINCT game_players.game_uuid
+         FROM game_players JOIN games ON games.uuid = game_players.game_uuid
+         WHERE game_players.disconnected_at <= ?1 AND games.status != 'finished'",
+    )?;
+    let games = stmt
+        .query_map(params![cutoff], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+
+    Ok(games)
+}
+
+/// Marks everyone still recorded as connected as disconnected at `now`. Live
+/// connections don't survive a restart, so this runs at startup; players who
+/// reconnect in time carry on as before.
+pub(crate) fn reset_connections(conn: &mut Connection, now: u64) -> Result<(), AppError> {
+    let tx = conn.transaction()?;
+
+    // Bumping the version makes anyone holding the old state re-read it.
+    tx.execute(
+        "UPDATE games SET version = version + 1 WHERE uuid IN (
+             SELECT game_uuid FROM game_players WHERE connected_at IS NOT NULL
+         )",
+        [],
+    )?;
+    tx.execute(
+        "UPDATE game_players SET connected_at = NULL, away_at = NULL, disconnected_at = ?1
+         WHERE connected_at IS NOT NULL",
+        params![now],
+    )?;
+
+    tx.commit()?;
+    Ok(())
+}
+
+/// The newest unfinished game the user is playing in, and its join code.
+pub(crate) fn active_game(
+    conn: &Connection,
+    user_id: &str,
+) -> Result<Option<(String, String)>, AppError> {
+    let game = conn
+        .query_row(
+            "SELECT games.uuid, game_codes.code
+             FROM game_players
+             JOIN games ON games.uuid = game_players.game_uuid
+             JOIN game_codes ON game_codes.game_uuid = games.uuid
+             WHERE game_players.user_id = ?1 AND games.status != 'finished'
+             ORDER BY games.created_at DESC, games.rowid DESC
+             LIMIT 1",
+            params![user_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+
+    Ok(game)
+}
+
+/// Every unfinished game the user is playing in.
+pub(crate) fn unfinished_games(conn: &Connection, user_id: &str) -> Result<Vec<String>, AppError> {
+    let mut stmt = conn.prepare(
+        "SELECT games.uuid
+         FROM game_players JOIN games ON games.uuid = game_players.game_uuid
+         WHERE game_players.user_id = ?1 AND games.status != 'finished'",
+    )?;
+    let games = stmt
+        .query_map(params![user_id], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+
+    Ok(games)
+}
+
+/// Games waiting on an image for their drawn prompt.
+pub(crate) fn generating_games(conn: &Connection) -> Result<Vec<GameState>, AppError> {
+    let mut stmt = conn.prepare("SELECT uuid FROM games WHERE status = 'generating'")?;
+    let games = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    games
+        .iter()
+        .map(|game_uuid| load_game_state(conn, game_uuid))
+        .collect()
+}
+
+pub(crate) fn load_game_state(conn: &Connection, game_uuid: &str) -> Result<GameState, AppError> {
+    let (mut game_state, status) = conn
+        .query_row(
+            "SELECT total_rounds, min_players, max_players, current_round, host_id, status,
+                 version, imagining_seconds, guessing_seconds, image_model, image_size,
+                 image_quality, scoring, language, theme, visibility, phase_deadline,
+                 image_failures
+             FROM games WHERE uuid = ?1",
+            params![game_uuid],
+            |row| {
+                let settings = GameSettings {
+                    imagining_seconds: row.get(7)?,
+                    guessing_seconds: row.get(8)?,
+                    image_model: row.get(9)?,
+                    image_size: row.get(10)?,
+                    image_quality: row.get(11)?,
+                    scoring: parse_column(row, 12)?,
+                    language: row.get(13)?,
+                    theme: row.get(14)?,
+                    visibility: parse_column(row, 15)?,
+                };
+                let mut game_state = GameState::new(
+                    game_uuid.to_string(),
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    settings,
+                );
+                game_state.current_round = row.get(3)?;
+                game_state.host_id = row.get::<_, Option<String>>(4)?.unwrap_or_default();
+                game_state.phase_deadline = row.get(16)?;
+                game_state.image_failures = row.get(17)?;
+                game_state.version = row.get(6)?;
+                Ok((game_state, row.get::<_, String>(5)?))
+            },
+        )
+        .optional()?
+        .ok_or_else(|| AppError::NotFound("Game not found".to_string()))?;
+
+    game_state.status = status.parse().map_err(|_| {
+        AppError::Internal(format!("Game {} has unknown status {}", game_uuid, status))
+    })?;
+    game_state.players = load_players(conn, game_uuid)?;
+    game_state.kicked = load_kicked(conn, game_uuid)?;
+    game_state.round_results = load_round_results(conn, game_uuid)?;
+
+    if game_state.status == GamePhase::Finished {
+        game_state.final_standings = game_state.standings();
+    } else {
+        load_current_round(conn, &mut game_state)?;
+    }
+
+    Ok(game_state)
+}
+
+/// Reads a column stored as the name of an enum variant.
+fn parse_column<T: FromStr>(row: &Row, index: usize) -> rusqlite::Result<T> {
+    let value: String = row.get(index)?;
+    value.parse().map_err(|_| {
+        rusqlite::Error::FromSqlConversionFailure(
+            index,
+            Type::Text,
+            format!("unknown value {:?}", value).into(),
+        )
+    })
+}
+
+fn load_players(conn: &Connection, game_uuid: &str) -> Result<Vec<Player>, AppError> {
+    let mut stmt = conn.prepare(
+        "SELECT users.id, users.username, users.deleted_at IS NOT NULL,
+                game_players.score, game_players.ready, game_players.afk,
+                game_players.connected_at, game_players.away_at, game_players.disconnected_at
+         FROM game_players JOIN users ON users.id = game_players.user_id
+         WHERE game_players.game_uuid = ?1
+         ORDER BY game_players.seat",
+    )?;
+
+    let players = stmt
+        .query_map(params![game_uuid], |row| {
+            let deleted: bool = row.get(2)?;
+            Ok(Player {
+                id: row.get(0)?,
+                username: if deleted {
+                    DELETED_USERNAME.to_string()
+                } else {
+                    row.get(1)?
+                },
+                score: row.get(3)?,
+                ready: row.get(4)?,
+                afk: row.get(5)?,
+                connected_at: row.get(6)?,
+                away_at: row.get(7)?,
+                disconnected_at: row.get(8)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+
+    Ok(players)
+}
+
+fn load_kicked(conn: &Connection, game_uuid: &str) -> Result<Vec<String>, AppError> {
+    let mut stmt = conn.prepare("SELECT user_id FROM game_kicks WHERE game_uuid = ?1")?;
+    let kicked = stmt
+        .query_map(params![game_uuid], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+
+    Ok(kicked)
+}
+
+fn load_round_results(conn: &Connection, game_uuid: &str) -> Result<Vec<RoundResult>, AppError> {
+    let mut stmt = conn.prepare(
+        "SELECT rounds.round, prompts.author_id, prompts.text, COALESCE(images.url, '')
+         FROM rounds
+         JOIN prompts ON prompts.id = rounds.prompt_id
+         LEFT JOIN images ON images.prompt_id = prompts.id
+         WHERE rounds.game_uuid = ?1 AND rounds.finished_at IS NOT NULL
+         ORDER BY rounds.round",
+    )?;
+
+    let mut results: Vec<RoundResult> = stmt
+        .query_map(params![game_uuid], |row| {
+            Ok(RoundResult {
+                round: row.get(0)?,
+                author_id: row.get(1)?,
+                prompt: row.get(2)?,
+                image: row.get(3)?,
+                guesses: vec![],
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+
+    for result in &mut results {
+        result.guesses = load_guesses(conn, game_uuid, result.round)?;
+    }
+
+    Ok(results)
+}
+
+/// Fills in the prompts, drawn prompt, image and guesses of the round being
+/// played.
+fn load_current_round(conn: &Connection, game_state: &mut GameState) -> Result<(), AppError> {
+    let game_uuid = game_state.game_id.as_str();
+    let round = game_state.current_round;
+
+    let mut stmt = conn.prepare(
+        "SELECT author_id, text FROM prompts
+         WHERE game_uuid = ?1 AND round = ?2
+         ORDER BY id",
+    )?;
+    game_state.submitted_prompts = stmt
+        .query_map(params![game_uuid, round], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?
+        .collect::<Result<_, _>>()?;
+
+    let drawn: Option<(String, String, String)> = conn
+        .query_row(
+            "SELECT prompts.author_id, prompts.text, COALESCE(images.url, '')
+             FROM rounds
+             JOIN prompts ON prompts.id = rounds.prompt_id
+             LEFT JOIN images ON images.prompt_id = prompts.id
+             WHERE rounds.game_uuid = ?1 AND rounds.round = ?2
+                 AND rounds.finished_at IS NULL",
+            params![game_uuid, round],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()?;
+
+    if let Some((author, prompt, image)) = drawn {
+        game_state.current_prompt_author = author;
+        game_state.current_prompt = prompt;
+        game_state.current_image = image;
+        game_state.submitted_guesses = load_guesses(conn, game_uuid, round)?
+            .into_iter()
+            .map(|guess| (guess.player_id, guess.guess, guess.score.to_string()))
+            .collect();
+    }
+
+    Ok(())
+}
+
+fn load_guesses(
+    conn: &Connection,
+    game_uuid: &str,
+    round: i32,
+) -> Result<Vec<ScoredGuess>, AppError> {
+    let mut stmt = conn.prepare(
+        "SELECT player_id, guess, score FROM guesses
+         WHERE game_uuid = ?1 AND round = ?2
+         ORDER BY submitted_at, rowid",
+    )?;
+
+    let guesses = stmt
+        .query_map(params![game_uuid, round], |row| {
+            Ok(ScoredGuess {
+                player_id: row.get(0)?,
+                guess: row.get(1)?,
+                score: row.get(2)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+
+    Ok(guesses)
+}
+
+/// Writes `game_state` over the stored game if it is still at `version`.
+/// Returns false, without writing anything, if someone else got there first.
+fn save_game(conn: &Connection, game_state: &GameState, version: i64) -> Result<bool, AppError> {
+    // A savepoint rather than a transaction, so callers can save a game as
+    // part of a larger transaction of their own.
+    conn.execute_batch("SAVEPOINT save_game")?;
+
+    let saved = write_game(conn, game_state, version);
+    match saved {
+        Ok(true) => conn.execute_batch("RELEASE save_game")?,
+        _ => conn.execute_batch("ROLLBACK TO save_game; RELEASE save_game")?,
+    }
+
+    saved
+}
+
+fn write_game(conn: &Connection, game_state: &GameState, version: i64) -> Result<bool, AppError> {
+    let updated = conn.execute(
+        "UPDATE games SET status = ?1, current_round = ?2, total_rounds = ?3, host_id = ?4,
+             min_players = ?5, max_players = ?6, phase_deadline = ?7, image_failures = ?8,
+             version = version + 1
+         WHERE uuid = ?9 AND version = ?10",
+        params![
+            game_state.status.as_str(),
+            game_state.current_round,
+            game_state.total_rounds,
+            host_id(game_state),
+            game_state.min_players,
+            game_state.max_players,
+            game_state.phase_deadline,
+            game_state.image_failures,
+            game_state.game_id,
+            version
+        ],
+    )?;
+
+    if updated == 0 {
+        return Ok(false);
+    }
+
+    write_players(conn, game_state)?;
+    write_rounds(conn, game_state)?;
+
+    Ok(true)
+}
+
+fn write_players(conn: &Connection, game_state: &GameState) -> Result<(), AppError> {
+    let game_uuid = game_state.game_id.as_str();
+
+    for (seat, player) in game_state.players.iter().enumerate() {
+        conn.execute(
+            "INSERT INTO game_players (game_uuid, user_id, seat, score, ready, afk,
+                 connected_at, away_at, disconnected_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+             ON CONFLICT (game_uuid, user_id) DO UPDATE SET
+                 seat = excluded.seat, score = excluded.score, ready = excluded.ready,
+                 afk = excluded.afk, connected_at = excluded.connected_at,
+                 away_at = excluded.away_at, disconnected_at = excluded.disconnected_at",
+            params![
+                game_uuid,
+                player.id,
+                seat,
+                player.score,
+                player.ready,
+                player.afk,
+                player.connected_at,
+                player.away_at,
+                player.disconnected_at
+            ],
+        )?;
+    }
+
+    let mut stmt = conn.prepare("SELECT user_id FROM game_players WHERE game_uuid = ?1")?;
+    let stored = stmt
+        .query_map(params![game_uuid], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for user_id in stored.iter().filter(|id| !game_state.is_player(id)) {
+        conn.execute(
+            "DELETE FROM game_players WHERE game_uuid = ?1 AND user_id = ?2",
+            params![game_uuid, user_id],
+        )?;
+    }
+
+    for user_id in &game_state.kicked {
+        conn.execute(
+            "INSERT INTO game_kicks (game_uuid, user_id) VALUES (?1, ?2)
+             ON CONFLICT (game_uuid, user_id) DO NOTHING",
+            params![game_uuid, user_id],
+        )?;
+    }
+
+    Ok(())
+}
+
+fn host_id(game_state: &GameState) -> Option<&str> {
+    Some(game_state.host_id.as_str()).filter(|id| !id.is_empty())
+}
+
+fn write_rounds(conn: &Connection, game_state: &GameState) -> Result<(), AppError> {
+    let game_uuid = game_state.game_id.as_str();
+    let now = unix_now().as_secs();
+
+    // The guess that ends a round is saved together with the round's end, so
+    // it only shows up here.
+    for result in &game_state.round_results {
+        for guess in &result.guesses {
+            insert_guess(conn, game_uuid, result.round, guess, now)?;
+        }
+        conn.execute(
+            "UPDATE rounds SET finished_at = ?1
+             WHERE game_uuid = ?2 AND round = ?3 AND finished_at IS NULL",
+            params![now, game_uuid, result.round],
+        )?;
+    }
+
+    if game_state.status == GamePhase::Finished {
+        return Ok(());
+    }
+
+    let round = game_state.current_round;
+    for (author, prompt) in &game_state.submitted_prompts {
+        conn.execute(
+            "INSERT INTO prompts (game_uuid, round, author_id, text) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (game_uuid, round, author_id) DO NOTHING",
+            params![game_uuid, round, author, prompt],
+        )?;
+    }
+
+    if game_state.current_prompt_author.is_empty() {
+        conn.execute(
+            "DELETE FROM rounds WHERE game_uuid = ?1 AND round = ?2 AND finished_at IS NULL",
+            params![game_uuid, round],
+        )?;
+    } else {
+        let prompt_id: i64 = conn.query_row(
+            "SELECT id FROM prompts WHERE game_uuid = ?1 AND round = ?2 AND author_id = ?3",
+            params![game_uuid, round, game_state.current_prompt_author],
+            |row| row.get(0),
+        )?;
+
+        conn.execute(
+            "INSERT INTO rounds (game_uuid, round, prompt_id) VALUES (?1, ?2, ?3)
+             ON CONFLICT (game_uuid, round) DO UPDATE SET prompt_id = excluded.prompt_id",
+            params![game_uuid, round, prompt_id],
+        )?;
+
+        if !game_state.current_image.is_empty() {
+            conn.execute(
+                "INSERT INTO images (prompt_id, url) VALUES (?1, ?2)
+                 ON CONFLICT (prompt_id) DO UPDATE SET url = excluded.url",
+                params![prompt_id, game_state.current_image],
+            )?;
+        }
+
+        for (player_id, guess, score) in &game_state.submitted_guesses {
+            let guess = ScoredGuess {
+                player_id: player_id.clone(),
+                guess: guess.clone(),
+                score: score.parse().unwrap_or(0),
+            };
+            insert_guess(conn, game_uuid, round, &guess, now)?;
+        }
+    }
+
+    // Prompts dropped from the round because their image failed.
+    let mut stmt =
+        conn.prepare("SELECT author_id FROM prompts WHERE game_uuid = ?1 AND round = ?2")?;
+    let stored = stmt
+        .query_map(params![game_uuid, round], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for author in stored
+        .iter()
+        .filter(|author| !game_state.has_submitted_prompt(author))
+    {
+        conn.execute(
+            "DELETE FROM prompts WHERE game_uuid = ?1 AND round = ?2 AND author_id = ?3",
+            params![game_uuid, round, author],
+        )?;
+    }
+
+    Ok(())
+}
+
+fn insert_guess(
+    conn: &Connection,
+    game_uuid: &str,
+    round: i32,
+    guess: &ScoredGuess,
+    now: u64,
+) -> Result<(), AppError> {
+    conn.execute(
+        "INSERT INTO guesses (game_uuid, round, player_id, guess, score, submitted_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+         ON CONFLICT (game_uuid, round, player_id) DO NOTHING",
+        params![
+            game_uuid,
+            round,
+            guess.player_id,
+            guess.guess,
+            guess.score,
+            now
+        ],
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_state::{Presence, ScoringMode, Visibility};
+    use crate::migrations;
+
+    fn database(players: &[&str]) -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("PRAGMA foreign_keys = ON;").unwrap();
+        migrations::run(&mut conn).unwrap();
+        for id in players {
+            conn.execute(
+                "INSERT INTO users (id, username) VALUES (?1, ?2)",
+                params![id, format!("{}-name", id)],
+            )
+            .unwrap();
+        }
+        conn
+    }
+
+    fn stored_game(conn: &Connection, players: &[&str]) -> GameState {
+        let settings = GameSettings {
+            imagining_seconds: 45,
+            guessing_seconds: 30,
+            image_model: "dall-e-2".to_string(),
+            image_size: "512x512".to_string(),
+            image_quality: "standard".to_string(),
+            scoring: ScoringMode::Threshold,
+            language: "pt-BR".to_string(),
+            theme: Some("animals".to_string()),
+            visibility: Visibility::Public,
+        };
+        let mut state = GameState::new("game".to_string(), 2, 2, 6, settings);
+        for id in players {
+            let player = Player::new(id.to_string(), format!("{}-name", id));
+            state.add_player(player).unwrap();
+        }
+        insert_game(conn, &state).unwrap();
+        assert_stored(conn, &state);
+        state
+    }
+
+    /// Asserts that the stored game reads back exactly as `expected`.
+    fn assert_stored(conn: &Connection, expected: &GameState) {
+        let loaded = load_game_state(conn, &expected.game_id).unwrap();
+        assert_eq!(
+            serde_json::to_value(&loaded).unwrap(),
+            serde_json::to_value(expected).unwrap()
+        );
+        assert_eq!(loaded.kicked, expected.kicked);
+        assert_eq!(loaded.image_failures, expected.image_failures);
+        assert_eq!(loaded.version, expected.version);
+    }
+
+    /// Applies `change` through `update_game` and checks the saved result.
+    fn change(conn: &Connection, mut change: impl FnMut(&mut GameState)) -> GameState {
+        let (_, state, ()) = update_game(conn, "game", |game_state| {
+            change(game_state);
+            Ok(())
+        })
+        .unwrap();
+        assert_stored(conn, &state);
+        state
+    }
+
+    #[test]
+    fn games_read_back_as_saved_through_a_whole_game() {
+        let conn = database(&["a", "b", "c", "d"]);
+        stored_game(&conn, &["a", "b", "c", "d"]);
+
+        change(&conn, |state| {
+            state.remove_player("d");
+            state.kicked.push("d".to_string());
+        });
+        change(&conn, |state| {
+            for player in &mut state.players {
+                player.ready = true;
+            }
+            state.start("a").unwrap();
+        });
+        change(&conn, |state| {
+            state.set_presence("a", Presence::Connected, 100);
+            state.set_presence("b", Presence::Away, 110);
+            state.set_presence("c", Presence::Disconnected, 120);
+        });
+
+        let state = change(&conn, |state| {
+            for id in ["a", "b", "c"] {
+                state.record_prompt(id, &format!("{} prompt", id));
+            }
+        });
+        assert_eq!(state.status, GamePhase::Generating);
+
+        let state = change(&conn, |state| {
+            state.image_failed().unwrap();
+        });
+        assert_eq!(state.image_failures, 1);
+        assert_eq!(state.submitted_prompts.len(), 2);
+
+        let state = change(&conn, |state| {
+            state.image_ready("https://example.com/1.png".to_string());
+        });
+        let guessers: Vec<String> = state
+            .players
+            .iter()
+            .filter(|p| p.id != state.current_prompt_author)
+            .map(|p| p.id.clone())
+            .collect();
+
+        let state = change(&conn, |state| {
+            state.record_guess(&guessers[0], "a first guess", 42);
+        });
+        assert_eq!(state.submitted_guesses.len(), 1);
+
+        let state = change(&conn, |state| {
+            state.record_guess(&guessers[1], "", 0);
+        });
+        assert_eq!(state.status, GamePhase::Imagining);
+        assert_eq!(state.current_round, 2);
+        assert_eq!(state.round_results[0].guesses.len(), 2);
+
+        change(&conn, |state| {
+            state.record_prompt("b", "b's second prompt");
+        });
+
+        let state = change(&conn, |state| {
+            state.remove_player("b");
+            state.remove_player("c");
+        });
+        assert_eq!(state.status, GamePhase::Finished);
+        assert_eq!(state.final_standings.len(), 1);
+    }
+
+    #[test]
+    fn update_game_retries_a_change_that_lost_a_race() {
+        let conn = database(&["a", "b"]);
+        stored_game(&conn, &["a", "b"]);
+
+        let mut attempts = 0;
+        let (_, state, ()) = update_game(&conn, "game", |state| {
+            attempts += 1;
+            if attempts == 1 {
+                // Another request saves the game while this one is working.
+                conn.execute("UPDATE games SET version = version + 1", [])
+                    .unwrap();
+            }
+            state.players[1].ready = true;
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(attempts, 2);
+        assert_eq!(state.version, 2);
+        assert!(state.players[1].ready);
+        assert_stored(&conn, &state);
+    }
+
+    #[test]
+    fn update_game_gives_up_on_a_game_that_keeps_changing() {
+        let conn = database(&["a", "b"]);
+        let original = stored_game(&conn, &["a", "b"]);
+
+        let mut attempts = 0;
+        let result = update_game(&conn, "game", |state| {
+            attempts += 1;
+            conn.execute("UPDATE games SET version = version + 1", [])
+                .unwrap();
+            state.players[1].ready = true;
+            Ok(())
+        });
+
+        assert!(matches!(result, Err(AppError::Conflict(_))));
+        assert_eq!(attempts, MAX_UPDATE_ATTEMPTS);
+        let stored = load_game_state(&conn, "game").unwrap();
+        assert!(!stored.players[1].ready);
+        assert_eq!(
+            stored.version,
+            original.version + i64::from(MAX_UPDATE_ATTEMPTS)
+        );
+    }
+
+    #[test]
+    fn unchanged_games_are_not_saved() {
+        let conn = database(&["a", "b"]);
+        let original = stored_game(&conn, &["a", "b"]);
+
+        let (_, state, ()) = update_game(&conn, "game", |_| Ok(())).unwrap();
+
+        assert_eq!(state.version, original.version);
+        assert_stored(&conn, &original);
+    }
+}
@@ -0,0 +1,358 @@
+// game_timers.rs
+use crate::auth::unix_now;
+use crate::db::Database;
+use crate::error::AppError;
+use crate::game_events::{Checkpoint, GameEvent, GameEvents};
+use crate::game_handlers::spawn_round_image;
+use crate::game_state::GameState;
+use crate::game_store::{self, load_game_state, update_game};
+use crate::providers::ProviderRegistry;
+use actix_web::web;
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::time::Duration;
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+// A game that can't be moved along this many times in a row is finished, so
+// one broken game isn't retried forever.
+const MAX_FAILURES: u32 = 8;
+
+// The longest wait, in seconds, between attempts at a failing game.
+const MAX_RETRY_DELAY: u64 = 300;
+
+/// Games that couldn't be moved along, so they are retried with exponential
+/// backoff instead of on every sweep.
+#[derive(Default)]
+struct Failures {
+    games: HashMap<String, Failure>,
+}
+
+struct Failure {
+    count: u32,
+    retry_at: u64,
+}
+
+impl Failures {
+    /// Whether the game is due another attempt at `now`.
+    fn due(&self, game_uuid: &str, now: u64) -> bool {
+        self.games
+            .get(game_uuid)
+            .is_none_or(|failure| failure.retry_at <= now)
+    }
+
+    /// Forgets games that no longer need moving along, however that came
+    /// about.
+    fn retain(&mut self, game_uuids: &[String]) {
+        self.games
+            .retain(|game_uuid, _| game_uuids.contains(game_uuid));
+    }
+
+    fn succeeded(&mut self, game_uuid: &str) {
+        self.games.remove(game_uuid);
+    }
+
+    /// Records a failed attempt and schedules the next one. Returns whether
+    /// the game has failed too often and should be given up on.
+    fn failed(&mut self, game_uuid: &str, now: u64) -> bool {
+        let failure = self.games.entry(game_uuid.to_string()).or_insert(Failure {
+            count: 0,
+            retry_at: now,
+        });
+        failure.count += 1;
+        failure.retry_at = now + (1u64 << failure.count.min(16)).min(MAX_RETRY_DELAY);
+
+        failure.count >= MAX_FAILURES
+    }
+}
+
+/// Moves games along when a phase runs out of time, and removes players who
+/// stay disconnected for longer than `reconnect_grace` seconds, for as long
+/// as the server runs. Deadlines live in the database, so after a restart
+/// games pick up where they left off, and any image that was being generated
+/// is requested again.
+pub(crate) async fn run(
+    db: web::Data<Database>,
+    registry: web::Data<ProviderRegistry>,
+    events: web::Data<GameEvents>,
+    reconnect_grace: u64,
+) {
+    let now = unix_now().as_secs();
+    if let Err(e) = db
+        .run(move |conn| game_store::reset_connections(conn, now))
+        .await
+    {
+        eprintln!("Error resetting player connections: {}", e);
+    }
+
+    match db.run(|conn| game_store::generating_games(conn)).await {
+        Ok(games) => {
+            for game_state in games {
+                let prompt = game_state.current_prompt.clone();
+                spawn_round_image(
+                    db.clone(),
+                    registry.clone(),
+                    events.clone(),
+                    &game_state,
+                    prompt,
+                );
+            }
+        }
+        Err(e) => eprintln!("Error resuming image generation: {}", e),
+    }
+
+    let mut expire_failures = Failures::default();
+    let mut drop_failures = Failures::default();
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        sweep(&db, &registry, &events, &mut expire_failures).await;
+        drop_disconnected(&db, &registry, &events, reconnect_grace, &mut drop_failures).await;
+    }
+}
+
+async fn sweep(
+    db: &web::Data<Database>,
+    registry: &web::Data<ProviderRegistry>,
+    events: &web::Data<GameEvents>,
+    failures: &mut Failures,
+) {
+    let now = unix_now().as_secs();
+    let expired = match db
+        .run(move |conn| game_store::expired_games(conn, now))
+        .await
+    {
+        Ok(expired) => expired,
+        Err(e) => {
+            eprintln!("Error looking up expired games: {}", e);
+            return;
+        }
+    };
+
+    failures.retain(&expired);
+
+    for game_uuid in expired {
+        if !failures.due(&game_uuid, now) {
+            continue;
+        }
+
+        let outcome = db
+            .run({
+                let events = events.clone();
+                let game_uuid = game_uuid.clone();
+                move |conn| expire_game(conn, &events, &game_uuid, now)
+            })
+            .await;
+
+        match outcome {
+            Ok(Some((game_state, prompt))) => {
+                failures.succeeded(&game_uuid);
+                spawn_round_image(
+                    db.clone(),
+                    registry.clone(),
+                    events.clone(),
+                    &game_state,
+                    prompt,
+                );
+            }
+            Ok(None) => failures.succeeded(&game_uuid),
+            Err(e) => {
+                eprintln!("Error advancing game {}: {}", game_uuid, e);
+                if failures.failed(&game_uuid, now) {
+                    give_up(db, events, failures, &game_uuid).await;
+                }
+            }
+        }
+    }
+}
+
+async fn drop_disconnected(
+    db: &web::Data<Database>,
+    registry: &web::Data<ProviderRegistry>,
+    events: &web::Data<GameEvents>,
+    reconnect_grace: u64,
+    failures: &mut Failures,
+) {
+    let now = unix_now().as_secs();
+    let cutoff = unix_now().as_secs().saturating_sub(reconnect_grace);
+    let games = match db
+        .run(move |conn| game_store::games_with_disconnected_players(conn, cutoff))
+        .await
+    {
+        Ok(games) => games,
+        Err(e) => {
+            eprintln!("Error looking up disconnected players: {}", e);
+            return;
+        }
+    };
+
+    failures.retain(&games);
+
+    for game_uuid in games {
+        if !failures.due(&game_uuid, now) {
+            continue;
+        }
+
+        let outcome = db
+            .run({
+                let events = events.clone();
+                let game_uuid = game_uuid.clone();
+                move |conn| drop_players(conn, &events, &game_uuid, cutoff)
+            })
+            .await;
+
+        match outcome {
+            Ok(Some((game_state, prompt))) => {
+                failures.succeeded(&game_uuid);
+                spawn_round_image(
+                    db.clone(),
+                    registry.clone(),
+                    events.clone(),
+                    &game_state,
+                    prompt,
+                );
+            }
+            Ok(None) => failures.succeeded(&game_uuid),
+            Err(e) => {
+                eprintln!("Error removing players from game {}: {}", game_uuid, e);
+                if failures.failed(&game_uuid, now) {
+                    give_up(db, events, failures, &game_uuid).await;
+                }
+            }
+        }
+    }
+}
+
+/// Finishes a game that has failed to move along too many times, and sends
+/// its sockets the final state. If even that fails, the game stays on its
+/// backoff and is tried again later.
+async fn give_up(
+    db: &web::Data<Database>,
+    events: &web::Data<GameEvents>,
+    failures: &mut Failures,
+    game_uuid: &str,
+) {
+    eprintln!(
+        "Giving up on game {} after {} failed attempts; finishing it",
+        game_uuid, MAX_FAILURES
+    );
+
+    let outcome = db
+        .run({
+            let events = events.clone();
+            let game_uuid = game_uuid.to_string();
+            move |conn| {
+                game_store::finish_game(conn, &game_uuid)?;
+                let game_state = load_game_state(conn, &game_uuid)?;
+                events.publish(
+                    Checkpoint::of(&game_state),
+                    &game_state,
+                    Some(GameEvent::Snapshot),
+                );
+                Ok(())
+            }
+        })
+        .await;
+
+    match outcome {
+        Ok(()) => failures.succeeded(game_uuid),
+        Err(e) => eprintln!("Error finishing game {}: {}", game_uuid, e),
+    }
+}
+
+/// Removes the players who have been disconnected since `cutoff`. Returns
+/// the game and the prompt to illustrate if that drew one.
+fn drop_players(
+    conn: &Connection,
+    events: &GameEvents,
+    game_uuid: &str,
+    cutoff: u64,
+) -> Result<Option<(GameState, String)>, AppError> {
+    let (before, game_state, (player_ids, prompt)) = update_game(conn, game_uuid, |game_state| {
+        let player_ids = game_state.disconnected_since(cutoff);
+        let mut prompt = None;
+        for player_id in &player_ids {
+            prompt = game_state.remove_player(player_id).or(prompt);
+        }
+        Ok((player_ids, prompt))
+    })?;
+
+    if player_ids.is_empty() {
+        return Ok(None);
+    }
+    events.publish(
+        before,
+        &game_state,
+        Some(GameEvent::PlayersDropped { player_ids }),
+    );
+
+    Ok(prompt.map(|prompt| (game_state, prompt)))
+}
+
+/// Ends the game's current phase if it is still out of time. Returns the game
+/// and the prompt to illustrate if that drew one.
+fn expire_game(
+    conn: &Connection,
+    events: &GameEvents,
+    game_uuid: &str,
+    now: u64,
+) -> Result<Option<(GameState, String)>, AppError> {
+    let (before, game_state, event_and_prompt) = update_game(conn, game_uuid, |game_state| {
+        // Everyone may have acted since we looked.
+        if !game_state.deadline_passed(now) {
+            return Ok(None);
+        }
+
+        let phase = game_state.status;
+        let (player_ids, prompt) = game_state.expire_phase();
+        Ok(Some((
+            GameEvent::PhaseTimedOut { phase, player_ids },
+            prompt,
+        )))
+    })?;
+
+    let Some((event, prompt)) = event_and_prompt else {
+        return Ok(None);
+    };
+    events.publish(before, &game_state, Some(event));
+
+    Ok(prompt.map(|prompt| (game_state, prompt)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failing_games_back_off_and_are_given_up_on() {
+        let mut failures = Failures::default();
+        assert!(failures.due("game", 100));
+
+        assert!(!failures.failed("game", 100));
+        assert!(!failures.due("game", 101));
+        assert!(failures.due("game", 102));
+
+        let mut now = 102;
+        for _ in 2..MAX_FAILURES {
+            assert!(!failures.failed("game", now));
+            now = failures.games["game"].retry_at;
+        }
+        assert!(failures.failed("game", now));
+        assert!(failures.games["game"].retry_at - now <= MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn recovered_or_moved_on_games_are_forgotten() {
+        let mut failures = Failures::default();
+        failures.failed("a", 100);
+        failures.failed("b", 100);
+        failures.failed("c", 100);
+
+        failures.succeeded("a");
+        failures.retain(&["a".to_string(), "b".to_string()]);
+
+        assert!(failures.due("a", 100));
+        assert!(!failures.due("b", 100));
+        assert!(failures.due("c", 100));
+    }
+}
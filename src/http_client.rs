@@ -0,0 +1,159 @@
+// http_client.rs
+use crate::error::AppError;
+use rand::Rng;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use std::time::Duration;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+const POOL_MAX_IDLE_PER_HOST: usize = 16;
+const TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+
+/// The kinds of upstream call we make, each with its own time budget.
+#[derive(Clone, Copy, Debug)]
+pub enum Operation {
+    Chat,
+    ChatStream,
+    Image,
+    Speech,
+    Transcription,
+    Embeddings,
+}
+
+impl Operation {
+    fn timeout(self) -> Duration {
+        match self {
+            Operation::Chat => Duration::from_secs(60),
+            // Covers the whole streamed body, not just the first byte.
+            Operation::ChatStream => Duration::from_secs(300),
+            Operation::Image => Duration::from_secs(120),
+            Operation::Speech => Duration::from_secs(60),
+            Operation::Transcription => Duration::from_secs(120),
+            Operation::Embeddings => Duration::from_secs(30),
+        }
+    }
+
+    /// Whether a request that timed out may be sent again. A timed-out image
+    /// request may still be generating, and billed, upstream.
+    fn retries_timeouts(self) -> bool {
+        !matches!(self, Operation::Image)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(20),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff with full jitter: a random delay between zero and
+    /// `base_delay * 2^attempt`, capped at `max_delay`.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+
+        rand::thread_rng().gen_range(Duration::ZERO..=ceiling)
+    }
+}
+
+/// One pooled HTTP client shared by every AI provider, retrying transient
+/// failures (429, 5xx, refused connections and, except for images, timeouts)
+/// with backoff.
+#[derive(Clone)]
+pub struct HttpClient {
+    client: Client,
+    retry: RetryPolicy,
+}
+
+impl HttpClient {
+    pub fn new(retry: RetryPolicy) -> reqwest::Result<Self> {
+        let client = Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+            .pool_max_idle_per_host(POOL_MAX_IDLE_PER_HOST)
+            .tcp_keepalive(TCP_KEEPALIVE)
+            .build()?;
+
+        Ok(Self { client, retry })
+    }
+
+    /// Sends the request produced by `build`, rebuilding it for each retry.
+    /// Non-success responses that aren't worth retrying are returned as-is
+    /// for the caller to interpret.
+    pub async fn send<F>(&self, operation: Operation, build: F) -> Result<Response, AppError>
+    where
+        F: Fn(&Client) -> RequestBuilder,
+    {
+        let mut attempt = 0;
+
+        loop {
+            let result = build(&self.client)
+                .timeout(operation.timeout())
+                .send()
+                .await;
+            let retries_left = attempt < self.retry.max_retries;
+
+            let delay = match result {
+                Ok(response) if retries_left && is_retryable(response.status()) => {
+                    match retry_after(&response) {
+                        // The provider wants us to back off longer than we're
+                        // willing to wait, so let the client see the 429.
+                        Some(delay) if delay > self.retry.max_delay => return Ok(response),
+                        Some(delay) => delay,
+                        None => self.retry.backoff(attempt),
+                    }
+                }
+                Ok(response) => return Ok(response),
+                Err(e)
+                    if retries_left
+                        && (e.is_connect() || (e.is_timeout() && operation.retries_timeouts())) =>
+                {
+                    self.retry.backoff(attempt)
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            eprintln!(
+                "Retrying {:?} request in {:?} (attempt {} of {})",
+                operation,
+                delay,
+                attempt + 1,
+                self.retry.max_retries
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    // 529 is Anthropic's "overloaded" status.
+    matches!(status.as_u16(), 429 | 500 | 502 | 503 | 504 | 529)
+}
+
+pub(crate) fn retry_after(response: &Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
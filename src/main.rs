@@ -1,45 +1,37 @@
 use actix_cors::Cors;
 use actix_web::http::header;
 use actix_web::{web, App, HttpServer};
-use rusqlite::Connection;
+use auth::JwtKeys;
+use config::Config;
+use db::Database;
+use game_events::GameEvents;
+use http_client::{HttpClient, RetryPolicy};
+use providers::ProviderRegistry;
+use std::env;
+use std::process;
+use usernames::UsernamePolicy;
 
 mod ai_handlers;
+mod auth;
+mod config;
+mod db;
+mod error;
+mod game_events;
 mod game_handlers;
+mod game_state;
+mod game_store;
+mod game_timers;
+mod http_client;
+mod migrations;
+mod providers;
 mod user_handlers;
+mod usernames;
 
-async fn create_tables(conn: &Connection) -> Result<(), rusqlite::Error> {
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS game_codes (
-            code TEXT PRIMARY KEY,
-            game_uuid TEXT NOT NULL
-        )",
-        [],
-    )?;
-
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS games (
-            uuid TEXT PRIMARY KEY,
-            state TEXT NOT NULL
-        )",
-        [],
-    )?;
-
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS users (
-            id TEXT PRIMARY KEY,
-            username TEXT UNIQUE NOT NULL
-        )",
-        [],
-    )?;
-
-    Ok(())
-}
-
-fn configure_cors() -> Cors {
-    Cors::default()
-        .allowed_origin("https://guess-ai.app")
-        .allowed_origin("http://localhost:3000")
-        .allowed_methods(vec!["GET", "POST"])
+fn configure_cors(origins: &[String]) -> Cors {
+    origins
+        .iter()
+        .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+        .allowed_methods(vec!["GET", "POST", "PATCH", "DELETE"])
         .allowed_headers(vec![header::AUTHORIZATION, header::ACCEPT])
         .allowed_header(header::CONTENT_TYPE)
         .max_age(3600)
@@ -47,6 +39,10 @@ fn configure_cors() -> Cors {
 
 fn configure_routes(cfg: &mut web::ServiceConfig) {
     cfg.route("/generate_chat", web::post().to(ai_handlers::generate_chat))
+        .route(
+            "/generate_chat/stream",
+            web::post().to(ai_handlers::generate_chat_stream),
+        )
         .route(
             "/generate_image",
             web::post().to(ai_handlers::generate_image),
@@ -59,30 +55,155 @@ fn configure_routes(cfg: &mut web::ServiceConfig) {
             "/transcribe_speech",
             web::post().to(ai_handlers::transcribe_speech),
         )
+        .route(
+            "/get_embeddings",
+            web::post().to(ai_handlers::get_embeddings),
+        )
         .route("/create_game", web::post().to(game_handlers::create_game))
+        .route("/game_state", web::post().to(game_handlers::get_game_state))
         .route("/join_game", web::post().to(game_handlers::join_game))
+        .route("/leave_game", web::post().to(game_handlers::leave_game))
+        .route(
+            "/my_active_game",
+            web::get().to(game_handlers::my_active_game),
+        )
+        .route("/kick_player", web::post().to(game_handlers::kick_player))
         .route("/player_ready", web::post().to(game_handlers::player_ready))
+        .route("/start_game", web::post().to(game_handlers::start_game))
         .route(
             "/submit_prompt",
             web::post().to(game_handlers::submit_prompt),
         )
-        .route("/score_guess", web::post().to(game_handlers::score_guess))
-        .route("/create_user", web::post().to(user_handlers::create_user));
+        .route("/submit_guess", web::post().to(game_handlers::submit_guess))
+        .route("/create_user", web::post().to(user_handlers::create_user))
+        .route("/login", web::post().to(user_handlers::login))
+        .route("/refresh", web::post().to(user_handlers::refresh))
+        .route("/logout", web::post().to(user_handlers::logout))
+        .route("/users/claim", web::post().to(user_handlers::claim_user))
+        .route("/users/me", web::patch().to(user_handlers::update_user))
+        .route("/users/me", web::delete().to(user_handlers::delete_user))
+        .route(
+            "/users/me/avatar",
+            web::post().to(user_handlers::generate_avatar),
+        )
+        .route("/users/{id}", web::get().to(user_handlers::get_user))
+        .route(
+            "/users/{id}/avatar",
+            web::get().to(user_handlers::get_avatar),
+        )
+        .route("/ws/game/{uuid}", web::get().to(game_handlers::game_socket));
+}
+
+/// Brings the database schema up to date, reporting each migration applied.
+fn migrate(database: &Database) -> Result<(), String> {
+    let mut conn = database.get().map_err(|e| e.to_string())?;
+
+    for migration in migrations::run(&mut conn)? {
+        eprintln!(
+            "Applied migration {:04}_{}",
+            migration.version, migration.name
+        );
+    }
+
+    Ok(())
+}
+
+/// Handles `migrate` and `migrate status`, which work on the database without
+/// starting the server.
+fn run_migrate_command(database: &Database, args: &[String]) -> Result<(), String> {
+    match args {
+        [] => {
+            migrate(database)?;
+            let conn = database.get().map_err(|e| e.to_string())?;
+            println!(
+                "Database is at schema version {}",
+                migrations::current_version(&conn)?
+            );
+            Ok(())
+        }
+        [status] if status == "status" => {
+            let conn = database.get().map_err(|e| e.to_string())?;
+            let current = migrations::current_version(&conn)?;
+            for migration in migrations::MIGRATIONS {
+                let state = if migration.version <= current {
+                    "applied"
+                } else {
+                    "pending"
+                };
+                println!("{:04}_{}  {}", migration.version, migration.name, state);
+            }
+            Ok(())
+        }
+        _ => Err("Usage: rust-playground migrate [status]".to_string()),
+    }
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv::dotenv().ok();
 
-    let conn = Connection::open("game_database.db").expect("Failed to open database connection");
-    create_tables(&conn).await.expect("Failed to create tables");
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+
+    let database = Database::open(&config.database.path).expect("Failed to open database");
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.split_first() {
+        Some((command, rest)) if command == "migrate" => {
+            if let Err(e) = run_migrate_command(&database, rest) {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+            return Ok(());
+        }
+        Some((command, _)) => {
+            eprintln!("Unknown command: {}", command);
+            eprintln!("Usage: rust-playground [migrate [status]]");
+            process::exit(2);
+        }
+        None => {}
+    }
+
+    migrate(&database).expect("Failed to migrate the database");
+    let database = web::Data::new(database);
+
+    let http_client = HttpClient::new(RetryPolicy::default()).expect("Failed to build HTTP client");
+    let providers = web::Data::new(ProviderRegistry::from_config(&config.ai, &http_client));
+    let game_events = web::Data::new(GameEvents::default());
+    let jwt_keys = web::Data::new(JwtKeys::from_config(&config.auth));
+    let username_policy = web::Data::new(
+        UsernamePolicy::from_config(&config.usernames).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        }),
+    );
+    actix_web::rt::spawn(game_timers::run(
+        database.clone(),
+        providers.clone(),
+        game_events.clone(),
+        config.game.reconnect_grace_seconds,
+    ));
+
+    let bind_address = config.server.bind_address.clone();
+    let config = web::Data::new(config);
 
     HttpServer::new(move || {
         App::new()
-            .wrap(configure_cors())
+            .wrap(configure_cors(&config.server.cors_origins))
+            .app_data(web::JsonConfig::default().error_handler(error::extractor_error))
+            .app_data(web::QueryConfig::default().error_handler(error::extractor_error))
+            .app_data(web::PathConfig::default().error_handler(error::extractor_error))
+            .app_data(config.clone())
+            .app_data(database.clone())
+            .app_data(providers.clone())
+            .app_data(game_events.clone())
+            .app_data(jwt_keys.clone())
+            .app_data(username_policy.clone())
             .configure(configure_routes)
     })
-    .bind("127.0.0.1:8080")?
+    .bind(bind_address)?
     .run()
     .await
 }
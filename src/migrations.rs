@@ -0,0 +1,355 @@
+// migrations.rs
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A numbered change to the database schema, kept in `migrations/`. Pending
+/// migrations are applied in order and together, so a failing one leaves the
+/// database as it was. Released migrations are never edited; schema changes
+/// go in a new file.
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    sql: &'static str,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        sql: include_str!("../migrations/0001_initial.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "normalize_games",
+        sql: include_str!("../migrations/0002_normalize_games.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "lobby",
+        sql: include_str!("../migrations/0003_lobby.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "game_settings",
+        sql: include_str!("../migrations/0004_game_settings.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "phase_timers",
+        sql: include_str!("../migrations/0005_phase_timers.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "presence",
+        sql: include_str!("../migrations/0006_presence.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "avatar_images",
+        sql: include_str!("../migrations/0007_avatar_images.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "repair_undrawn_rounds",
+        sql: include_str!("../migrations/0008_repair_undrawn_rounds.sql"),
+    },
+    Migration {
+        version: 9,
+        name: "image_failures",
+        sql: include_str!("../migrations/0009_image_failures.sql"),
+    },
+];
+
+/// Applies every migration the database hasn't seen yet and returns the ones
+/// that were applied.
+pub fn run(conn: &mut Connection) -> Result<Vec<&'static Migration>, String> {
+    // Holding the write lock throughout keeps two servers starting at once
+    // from applying the same migration twice.
+    let tx = conn
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .map_err(|e| format!("Could not lock the database: {}", e))?;
+
+    let needs_baseline = !table_exists(&tx, "schema_version")? && table_exists(&tx, "games")?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            applied_at INTEGER NOT NULL
+        )",
+        [],
+    )
+    .map_err(|e| format!("Could not create schema_version: {}", e))?;
+
+    if needs_baseline {
+        eprintln!("Database predates migrations; bringing it up to the initial schema");
+        upgrade_legacy_schema(&tx)
+            .map_err(|e| format!("Could not upgrade the existing database: {}", e))?;
+    }
+
+    let current = current_version(&tx)?;
+    if let Some(latest) = MIGRATIONS.last() {
+        if current > latest.version {
+            return Err(format!(
+                "Database is at schema version {}, but this server only knows up to {}",
+                current, latest.version
+            ));
+        }
+    }
+
+    let mut applied = Vec::new();
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        tx.execute_batch(migration.sql)
+            .and_then(|_| {
+                tx.execute(
+                    "INSERT INTO schema_version (version, name, applied_at) VALUES (?1, ?2, ?3)",
+                    params![migration.version, migration.name, unix_now()],
+                )
+            })
+            .map_err(|e| {
+                format!(
+                    "Migration {:04}_{} failed: {}",
+                    migration.version, migration.name, e
+                )
+            })?;
+        applied.push(migration);
+    }
+
+    tx.commit()
+        .map_err(|e| format!("Could not commit migrations: {}", e))?;
+
+    Ok(applied)
+}
+
+/// Returns the schema version the database is at, 0 for a fresh database.
+pub fn current_version(conn: &Connection) -> Result<i64, String> {
+    if !table_exists(conn, "schema_version")? {
+        return Ok(0);
+    }
+
+    conn.query_row("SELECT MAX(version) FROM schema_version", [], |row| {
+        row.get::<_, Option<i64>>(0)
+    })
+    .map(Option::unwrap_or_default)
+    .map_err(|e| format!("Could not read the schema version: {}", e))
+}
+
+/// Databases created before migrations existed may lack columns that were
+/// added to existing tables over time. Adding them here lets the initial
+/// migration, which only creates what is missing, finish the job.
+fn upgrade_legacy_schema(conn: &Connection) -> Result<(), rusqlite::Error> {
+    add_column_if_missing(conn, "games", "version", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(conn, "users", "is_guest", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(conn, "users", "avatar", "TEXT")?;
+    rename_case_clashes(conn)?;
+
+    Ok(())
+}
+
+/// Usernames only became unique regardless of case later, so older databases
+/// may hold names like `Bob` and `bob`. The first user to take a name keeps
+/// it and the others get a numeric suffix, so the initial migration can
+/// create its case-insensitive index.
+fn rename_case_clashes(conn: &Connection) -> Result<(), rusqlite::Error> {
+    let clashes = conn
+        .prepare(
+            "SELECT id, username FROM users AS later
+             WHERE EXISTS (
+                 SELECT 1 FROM users AS earlier
+                 WHERE earlier.username = later.username COLLATE NOCASE
+                     AND earlier.rowid < later.rowid
+             )
+             ORDER BY rowid",
+        )?
+        .query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for (user_id, username) in clashes {
+        let mut taken = conn.prepare("SELECT 1 FROM users WHERE username = ?1 COLLATE NOCASE")?;
+        let mut suffix = 2;
+        let renamed = loop {
+            let candidate = format!("{}{}", username, suffix);
+            if !taken.exists(params![candidate])? {
+                break candidate;
+            }
+            suffix += 1;
+        };
+
+        conn.execute(
+            "UPDATE users SET username = ?1 WHERE id = ?2",
+            params![renamed, user_id],
+        )?;
+        eprintln!(
+            "Renamed user {} from {:?} to {:?}, as usernames now ignore case",
+            user_id, username, renamed
+        );
+    }
+
+    Ok(())
+}
+
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), rusqlite::Error> {
+    let exists = conn
+        .prepare(&format!(
+            "SELECT 1 FROM pragma_table_info('{}') WHERE name = ?1",
+            table
+        ))?
+        .exists([column])?;
+
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            [],
+        )?;
+    }
+
+    Ok(())
+}
+
+fn table_exists(conn: &Connection, table: &str) -> Result<bool, String> {
+    conn.query_row(
+        "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1",
+        params![table],
+        |_| Ok(()),
+    )
+    .optional()
+    .map(|row| row.is_some())
+    .map_err(|e| format!("Could not inspect the database: {}", e))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usernames(conn: &Connection) -> Vec<String> {
+        conn.prepare("SELECT username FROM users ORDER BY rowid")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    /// A database as the server created it before migrations existed.
+    fn legacy_database(usernames: &[&str]) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE game_codes (code TEXT PRIMARY KEY, game_uuid TEXT NOT NULL);
+             CREATE TABLE games (uuid TEXT PRIMARY KEY, state TEXT NOT NULL);
+             CREATE TABLE users (id TEXT PRIMARY KEY, username TEXT UNIQUE NOT NULL);",
+        )
+        .unwrap();
+        for (index, username) in usernames.iter().enumerate() {
+            conn.execute(
+                "INSERT INTO users (id, username) VALUES (?1, ?2)",
+                params![format!("user-{}", index), username],
+            )
+            .unwrap();
+        }
+        conn
+    }
+
+    #[test]
+    fn migrates_a_fresh_database_to_the_latest_version() {
+        let mut conn = Connection::open_in_memory().unwrap();
+
+        let applied = run(&mut conn).unwrap();
+        assert_eq!(applied.len(), MIGRATIONS.len());
+        assert_eq!(
+            current_version(&conn).unwrap(),
+            MIGRATIONS.last().unwrap().version
+        );
+
+        assert!(run(&mut conn).unwrap().is_empty());
+    }
+
+    #[test]
+    fn upgrades_a_legacy_database() {
+        let mut conn = legacy_database(&["alice", "bob"]);
+
+        run(&mut conn).unwrap();
+        assert_eq!(
+            current_version(&conn).unwrap(),
+            MIGRATIONS.last().unwrap().version
+        );
+        assert_eq!(usernames(&conn), ["alice", "bob"]);
+    }
+
+    #[test]
+    fn renames_legacy_usernames_that_differ_only_in_case() {
+        let mut conn = legacy_database(&["Bob", "alice", "bob", "bob2", "BOB"]);
+
+        run(&mut conn).unwrap();
+        assert_eq!(usernames(&conn), ["Bob", "alice", "bob3", "bob2", "BOB4"]);
+
+        let clash = conn.execute(
+            "INSERT INTO users (id, username) VALUES ('new', 'ALICE')",
+            [],
+        );
+        assert!(clash.is_err());
+    }
+
+    #[test]
+    fn legacy_games_without_a_drawn_prompt_go_back_to_imagining() {
+        let mut conn = legacy_database(&["alice", "bob"]);
+        let state = r#"{
+            "status": "guessing", "current_round": 1, "total_rounds": 3,
+            "players": [
+                {"id": "user-0", "score": 0, "ready": true},
+                {"id": "user-1", "score": 0, "ready": true}
+            ],
+            "current_prompt": "", "current_image": "",
+            "submitted_prompts": [["user-0", "a cat"], ["user-1", "a dog"]],
+            "submitted_guesses": []
+        }"#;
+        conn.execute(
+            "INSERT INTO games (uuid, state) VALUES ('game', ?1)",
+            params![state],
+        )
+        .unwrap();
+
+        run(&mut conn).unwrap();
+        let (status, deadline, prompts): (String, Option<u64>, i64) = conn
+            .query_row(
+                "SELECT status, phase_deadline,
+                     (SELECT COUNT(*) FROM prompts WHERE game_uuid = 'game')
+                 FROM games WHERE uuid = 'game'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(status, "imagining");
+        assert!(deadline.is_some_and(|deadline| deadline > unix_now()));
+        assert_eq!(prompts, 2);
+    }
+
+    #[test]
+    fn refuses_a_database_from_a_newer_server() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        run(&mut conn).unwrap();
+        conn.execute(
+            "INSERT INTO schema_version (version, name, applied_at) VALUES (999, 'future', 0)",
+            [],
+        )
+        .unwrap();
+
+        match run(&mut conn) {
+            Err(e) => assert!(e.contains("999"), "{}", e),
+            Ok(_) => panic!("migrated a database from a newer server"),
+        }
+    }
+}
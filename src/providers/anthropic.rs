@@ -0,0 +1,245 @@
+// providers/anthropic.rs
+use super::{malformed, send, sse, AiProvider, ChatEvent, ChatStream, ProviderResult};
+use crate::ai_handlers::{Message, RequestPayload};
+use crate::error::AppError;
+use crate::http_client::{HttpClient, Operation};
+use async_trait::async_trait;
+use futures::{future, stream, StreamExt};
+use serde::{Deserialize, Serialize};
+
+const API_VERSION: &str = "2023-06-01";
+const DEFAULT_MAX_TOKENS: u32 = 1024;
+
+#[derive(Serialize, Debug)]
+struct MessagesRequest<'a> {
+    model: &'a str,
+    max_tokens: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+    messages: Vec<&'a Message>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentBlock {
+    Text {
+        text: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize, Debug)]
+struct MessagesResponse {
+    content: Vec<ContentBlock>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct StreamUsage {
+    input_tokens: Option<u32>,
+    output_tokens: Option<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+struct StreamMessage {
+    #[serde(default)]
+    usage: StreamUsage,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum BlockDelta {
+    TextDelta {
+        text: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize, Debug)]
+struct MessageDeltaBody {
+    stop_reason: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct StreamError {
+    #[serde(rename = "type")]
+    kind: String,
+    message: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StreamEvent {
+    MessageStart {
+        message: StreamMessage,
+    },
+    ContentBlockDelta {
+        delta: BlockDelta,
+    },
+    MessageDelta {
+        delta: MessageDeltaBody,
+        #[serde(default)]
+        usage: StreamUsage,
+    },
+    MessageStop,
+    Error {
+        error: StreamError,
+    },
+    #[serde(other)]
+    Other,
+}
+
+/// Input tokens are reported in `message_start` and the stop reason in
+/// `message_delta`; both are carried forward to later events.
+#[derive(Default)]
+struct StreamState {
+    input_tokens: Option<u32>,
+    stop_reason: Option<String>,
+}
+
+impl StreamState {
+    fn apply(&mut self, event: StreamEvent) -> Vec<ProviderResult<ChatEvent>> {
+        match event {
+            StreamEvent::MessageStart { message } => {
+                self.input_tokens = message.usage.input_tokens;
+                vec![]
+            }
+            StreamEvent::ContentBlockDelta {
+                delta: BlockDelta::TextDelta { text },
+            } => vec![Ok(ChatEvent::Delta { text })],
+            StreamEvent::MessageDelta { delta, usage } => {
+                self.stop_reason = delta.stop_reason;
+                vec![Ok(ChatEvent::Usage {
+                    input_tokens: usage.input_tokens.or(self.input_tokens),
+                    output_tokens: usage.output_tokens,
+                })]
+            }
+            StreamEvent::MessageStop => vec![Ok(ChatEvent::Done {
+                stop_reason: self.stop_reason.take(),
+            })],
+            StreamEvent::Error { error } => {
+                vec![Err(AppError::from_provider_kind(
+                    Some(&error.kind),
+                    &error.message,
+                )
+                .unwrap_or(AppError::UpstreamUnavailable(error.message)))]
+            }
+            StreamEvent::ContentBlockDelta { .. } | StreamEvent::Other => vec![],
+        }
+    }
+}
+
+impl<'a> MessagesRequest<'a> {
+    /// The Messages API takes system instructions as a top-level field rather
+    /// than as a message, so any system messages are lifted out of the list.
+    fn from_payload(payload: &'a RequestPayload) -> Self {
+        let (system, messages): (Vec<&Message>, Vec<&Message>) = payload
+            .messages
+            .iter()
+            .partition(|message| message.role == "system");
+
+        let system = if system.is_empty() {
+            None
+        } else {
+            Some(
+                system
+                    .iter()
+                    .map(|message| message.content.as_str())
+                    .collect::<Vec<_>>()
+                    .join("\n\n"),
+            )
+        };
+
+        MessagesRequest {
+            model: &payload.model,
+            max_tokens: payload.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            system,
+            messages,
+            stream: false,
+        }
+    }
+}
+
+pub struct AnthropicProvider {
+    api_key: String,
+    base_url: String,
+    client: HttpClient,
+}
+
+impl AnthropicProvider {
+    pub fn new(api_key: String, base_url: String, client: HttpClient) -> Self {
+        Self {
+            api_key,
+            base_url,
+            client,
+        }
+    }
+
+    fn post(&self, client: &reqwest::Client, path: &str) -> reqwest::RequestBuilder {
+        client
+            .post(format!("{}{}", self.base_url, path))
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", API_VERSION)
+    }
+}
+
+#[async_trait]
+impl AiProvider for AnthropicProvider {
+    fn name(&self) -> &'static str {
+        "anthropic"
+    }
+
+    fn supports_model(&self, model: &str) -> bool {
+        model.starts_with("claude")
+    }
+
+    async fn chat(&self, payload: &RequestPayload) -> ProviderResult<String> {
+        let request = MessagesRequest::from_payload(payload);
+        let response: MessagesResponse = send(&self.client, Operation::Chat, |client| {
+            self.post(client, "/messages").json(&request)
+        })
+        .await?
+        .json()
+        .await?;
+
+        let text: String = response
+            .content
+            .into_iter()
+            .filter_map(|block| match block {
+                ContentBlock::Text { text } => Some(text),
+                ContentBlock::Other => None,
+            })
+            .collect();
+
+        Ok(text)
+    }
+
+    async fn chat_stream(&self, payload: &RequestPayload) -> ProviderResult<ChatStream> {
+        let mut request = MessagesRequest::from_payload(payload);
+        request.stream = true;
+
+        let response = send(&self.client, Operation::ChatStream, |client| {
+            self.post(client, "/messages").json(&request)
+        })
+        .await?;
+
+        let events = sse::decode(response)
+            .scan(StreamState::default(), |state, event| {
+                let events = match event {
+                    Ok(event) => match serde_json::from_str::<StreamEvent>(&event.data) {
+                        Ok(event) => state.apply(event),
+                        Err(e) => vec![Err(malformed("anthropic", e))],
+                    },
+                    Err(e) => vec![Err(e)],
+                };
+                future::ready(Some(events))
+            })
+            .flat_map(stream::iter)
+            .boxed();
+
+        Ok(events)
+    }
+}
@@ -0,0 +1,159 @@
+// providers/mock.rs
+use super::{AiProvider, ChatEvent, ChatStream, ProviderResult};
+use crate::ai_handlers::{
+    EmbeddingRequestPayload, ImageRequestPayload, RequestPayload, SpeechToTextRequestPayload,
+    TextToSpeechRequestPayload,
+};
+use crate::error::AppError;
+use async_trait::async_trait;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use futures::{stream, StreamExt};
+
+const EMBEDDING_DIMENSIONS: usize = 64;
+
+// A 1x1 grey PNG, base64-encoded. `image` returns it as a data URL so
+// clients can render it directly.
+const PLACEHOLDER_PNG: &str =
+    "iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAAAAAA6fptVAAAACklEQVR4nGNoAAAAggCBd81ytgAAAABJRU5ErkJggg==";
+
+// One MPEG-1 Layer III frame header: 128 kbit/s, 44.1 kHz, mono. With an
+// all-zero side info and main data section the frame decodes to silence.
+const MP3_FRAME_HEADER: [u8; 4] = [0xFF, 0xFB, 0x90, 0xC4];
+const MP3_FRAME_LENGTH: usize = 417;
+const MP3_FRAMES: usize = 38;
+
+const CANNED_REPLIES: &[&str] = &[
+    "A lighthouse made of stacked teacups glowing at dusk.",
+    "A fox in a raincoat reading a map under a streetlamp.",
+    "An astronaut tending a vegetable garden on the moon.",
+    "A steam train crossing a bridge built from piano keys.",
+    "A cat conducting an orchestra of rubber ducks.",
+];
+
+/// Deterministic stand-in for the real providers, for offline development
+/// and tests. Every response is derived from the request contents so the same
+/// input always yields the same output.
+pub struct MockProvider;
+
+impl MockProvider {
+    fn reply(payload: &RequestPayload) -> String {
+        let last_message = payload
+            .messages
+            .last()
+            .map(|message| message.content.as_str())
+            .unwrap_or_default();
+
+        let index = (fnv1a(last_message.as_bytes()) % CANNED_REPLIES.len() as u64) as usize;
+        CANNED_REPLIES[index].to_string()
+    }
+}
+
+#[async_trait]
+impl AiProvider for MockProvider {
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+
+    fn supports_model(&self, _model: &str) -> bool {
+        true
+    }
+
+    fn generates_images(&self) -> bool {
+        true
+    }
+
+    async fn chat(&self, payload: &RequestPayload) -> ProviderResult<String> {
+        Ok(Self::reply(payload))
+    }
+
+    async fn chat_stream(&self, payload: &RequestPayload) -> ProviderResult<ChatStream> {
+        let reply = Self::reply(payload);
+        let input_tokens = payload
+            .messages
+            .iter()
+            .map(|message| message.content.split_whitespace().count() as u32)
+            .sum();
+
+        let mut events: Vec<ProviderResult<ChatEvent>> = reply
+            .split_inclusive(' ')
+            .map(|word| {
+                Ok(ChatEvent::Delta {
+                    text: word.to_string(),
+                })
+            })
+            .collect();
+        events.push(Ok(ChatEvent::Usage {
+            input_tokens: Some(input_tokens),
+            output_tokens: Some(reply.split_whitespace().count() as u32),
+        }));
+        events.push(Ok(ChatEvent::Done {
+            stop_reason: Some("stop".to_string()),
+        }));
+
+        Ok(stream::iter(events).boxed())
+    }
+
+    async fn image(&self, _payload: &ImageRequestPayload) -> ProviderResult<String> {
+        Ok(format!("data:image/png;base64,{}", PLACEHOLDER_PNG))
+    }
+
+    async fn image_data(&self, _payload: &ImageRequestPayload) -> ProviderResult<Vec<u8>> {
+        BASE64_STANDARD
+            .decode(PLACEHOLDER_PNG)
+            .map_err(|e| AppError::Internal(e.to_string()))
+    }
+
+    async fn speech(&self, _payload: &TextToSpeechRequestPayload) -> ProviderResult<Vec<u8>> {
+        let mut frame = vec![0u8; MP3_FRAME_LENGTH];
+        frame[..MP3_FRAME_HEADER.len()].copy_from_slice(&MP3_FRAME_HEADER);
+
+        Ok(frame.repeat(MP3_FRAMES))
+    }
+
+    async fn transcription(
+        &self,
+        _payload: &SpeechToTextRequestPayload,
+        file_contents: &[u8],
+    ) -> ProviderResult<String> {
+        Ok(format!(
+            "This is a mock transcription of {} bytes of audio.",
+            file_contents.len()
+        ))
+    }
+
+    async fn embeddings(&self, payload: &EmbeddingRequestPayload) -> ProviderResult<Vec<Vec<f64>>> {
+        Ok(payload.input.iter().map(|text| embed(text)).collect())
+    }
+}
+
+/// Hashes each lowercase word into a fixed-size signed bag-of-words vector,
+/// so texts sharing words score as similar under cosine similarity.
+fn embed(text: &str) -> Vec<f64> {
+    let mut vector = vec![0.0; EMBEDDING_DIMENSIONS];
+    let normalized = text.to_lowercase();
+    let mut words = normalized
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .peekable();
+
+    // Give empty or punctuation-only input a direction of its own so the
+    // vector never has zero magnitude.
+    if words.peek().is_none() {
+        vector[(fnv1a(text.as_bytes()) as usize) % EMBEDDING_DIMENSIONS] = 1.0;
+        return vector;
+    }
+
+    for word in words {
+        let hash = fnv1a(word.as_bytes());
+        let sign = if hash & (1 << 63) == 0 { 1.0 } else { -1.0 };
+        vector[(hash as usize) % EMBEDDING_DIMENSIONS] += sign;
+    }
+
+    vector
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
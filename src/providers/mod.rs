@@ -0,0 +1,187 @@
+// providers/mod.rs
+use crate::ai_handlers::{
+    EmbeddingRequestPayload, ImageRequestPayload, RequestPayload, SpeechToTextRequestPayload,
+    TextToSpeechRequestPayload,
+};
+use crate::config::{AiBackend, AiConfig};
+use crate::error::AppError;
+use crate::http_client::{self, HttpClient, Operation};
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use serde::Serialize;
+use std::sync::Arc;
+
+mod anthropic;
+mod mock;
+mod openai;
+mod sse;
+
+pub use anthropic::AnthropicProvider;
+pub use mock::MockProvider;
+pub use openai::OpenAiProvider;
+
+pub type ProviderResult<T> = Result<T, AppError>;
+
+pub type ChatStream = BoxStream<'static, ProviderResult<ChatEvent>>;
+
+/// An incremental update from a streaming chat completion.
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum ChatEvent {
+    Delta {
+        text: String,
+    },
+    Usage {
+        input_tokens: Option<u32>,
+        output_tokens: Option<u32>,
+    },
+    Done {
+        stop_reason: Option<String>,
+    },
+}
+
+impl ChatEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChatEvent::Delta { .. } => "delta",
+            ChatEvent::Usage { .. } => "usage",
+            ChatEvent::Done { .. } => "done",
+        }
+    }
+}
+
+/// A backend capable of serving some or all of the AI operations exposed by
+/// `ai_handlers`. Operations a provider does not offer fall back to an error.
+#[async_trait]
+pub trait AiProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn supports_model(&self, model: &str) -> bool;
+
+    /// Whether the provider implements `image` and `image_data`.
+    fn generates_images(&self) -> bool {
+        false
+    }
+
+    async fn chat(&self, payload: &RequestPayload) -> ProviderResult<String>;
+
+    async fn chat_stream(&self, _payload: &RequestPayload) -> ProviderResult<ChatStream> {
+        Err(unsupported(self.name(), "streaming chat"))
+    }
+
+    async fn image(&self, _payload: &ImageRequestPayload) -> ProviderResult<String> {
+        Err(unsupported(self.name(), "image generation"))
+    }
+
+    /// Generates an image and returns its PNG bytes, for images that must
+    /// outlive the provider's hosted copy.
+    async fn image_data(&self, _payload: &ImageRequestPayload) -> ProviderResult<Vec<u8>> {
+        Err(unsupported(self.name(), "image generation"))
+    }
+
+    async fn speech(&self, _payload: &TextToSpeechRequestPayload) -> ProviderResult<Vec<u8>> {
+        Err(unsupported(self.name(), "speech generation"))
+    }
+
+    async fn transcription(
+        &self,
+        _payload: &SpeechToTextRequestPayload,
+        _file_contents: &[u8],
+    ) -> ProviderResult<String> {
+        Err(unsupported(self.name(), "transcription"))
+    }
+
+    async fn embeddings(
+        &self,
+        _payload: &EmbeddingRequestPayload,
+    ) -> ProviderResult<Vec<Vec<f64>>> {
+        Err(unsupported(self.name(), "embeddings"))
+    }
+}
+
+fn unsupported(provider: &str, operation: &str) -> AppError {
+    AppError::BadRequest(format!("{} does not support {}", provider, operation))
+}
+
+/// Sends a provider request, turning any non-success response into the
+/// matching `AppError` using the provider's error body.
+async fn send<F>(
+    client: &HttpClient,
+    operation: Operation,
+    build: F,
+) -> ProviderResult<reqwest::Response>
+where
+    F: Fn(&reqwest::Client) -> reqwest::RequestBuilder,
+{
+    let response = client.send(operation, build).await?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let retry_after = http_client::retry_after(&response).map(|delay| delay.as_secs());
+    let body = response.text().await.unwrap_or_default();
+
+    Err(AppError::from_upstream(status.as_u16(), retry_after, &body))
+}
+
+fn malformed(provider: &str, e: impl std::fmt::Display) -> AppError {
+    eprintln!("Malformed response from {}: {}", provider, e);
+    AppError::UpstreamUnavailable(format!("Unexpected response from {}", provider))
+}
+
+/// Providers registered at startup. They are consulted in registration order
+/// and the first one that supports a model serves the request.
+#[derive(Default)]
+pub struct ProviderRegistry {
+    providers: Vec<Arc<dyn AiProvider>>,
+}
+
+impl ProviderRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, provider: impl AiProvider + 'static) {
+        self.providers.push(Arc::new(provider));
+    }
+
+    /// Builds the registry from the configuration. The mock backend serves
+    /// every model from the offline mock provider instead of the real APIs.
+    pub fn from_config(config: &AiConfig, client: &HttpClient) -> Self {
+        let mut registry = Self::new();
+
+        if config.backend == AiBackend::Mock {
+            println!("AI_BACKEND=mock, serving all models from the mock provider");
+            registry.register(MockProvider);
+            return registry;
+        }
+
+        match &config.anthropic_api_key {
+            Some(api_key) => registry.register(AnthropicProvider::new(
+                api_key.clone(),
+                config.anthropic_base_url.trim_end_matches('/').to_string(),
+                client.clone(),
+            )),
+            None => eprintln!("ANTHROPIC_API_KEY not set, Claude models are unavailable"),
+        }
+
+        match &config.openai_api_key {
+            Some(api_key) => registry.register(OpenAiProvider::new(
+                api_key.clone(),
+                config.openai_base_url.trim_end_matches('/').to_string(),
+                client.clone(),
+            )),
+            None => eprintln!("OPENAI_API_KEY not set, OpenAI models are unavailable"),
+        }
+
+        registry
+    }
+
+    pub fn for_model(&self, model: &str) -> Option<&dyn AiProvider> {
+        self.providers
+            .iter()
+            .find(|provider| provider.supports_model(model))
+            .map(|provider| provider.as_ref())
+    }
+}
@@ -0,0 +1,278 @@
+// providers/openai.rs
+use super::{malformed, send, sse, AiProvider, ChatEvent, ChatStream, ProviderResult};
+use crate::ai_handlers::{
+    EmbeddingRequestPayload, ImageRequestPayload, Message, RequestPayload,
+    SpeechToTextRequestPayload, TextToSpeechRequestPayload,
+};
+use crate::error::AppError;
+use crate::http_client::{HttpClient, Operation};
+use async_trait::async_trait;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use futures::{future, stream, StreamExt};
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use serde::Deserialize;
+use serde_json::json;
+
+#[derive(Deserialize, Debug)]
+struct Choice {
+    message: Message,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatResponse {
+    choices: Vec<Choice>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChunkDelta {
+    content: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChunkChoice {
+    delta: ChunkDelta,
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChunkUsage {
+    prompt_tokens: u32,
+    completion_tokens: u32,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatChunk {
+    #[serde(default)]
+    choices: Vec<ChunkChoice>,
+    usage: Option<ChunkUsage>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ImageData {
+    url: Option<String>,
+    b64_json: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ImageResponse {
+    data: Vec<ImageData>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TranscriptionResponse {
+    text: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct EmbeddingData {
+    embedding: Vec<f64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct EmbeddingResponse {
+    data: Vec<EmbeddingData>,
+}
+
+pub struct OpenAiProvider {
+    api_key: String,
+    base_url: String,
+    client: HttpClient,
+}
+
+impl OpenAiProvider {
+    pub fn new(api_key: String, base_url: String, client: HttpClient) -> Self {
+        Self {
+            api_key,
+            base_url,
+            client,
+        }
+    }
+
+    fn post(&self, client: &reqwest::Client, path: &str) -> reqwest::RequestBuilder {
+        client
+            .post(format!("{}{}", self.base_url, path))
+            .header("Authorization", format!("Bearer {}", self.api_key))
+    }
+}
+
+#[async_trait]
+impl AiProvider for OpenAiProvider {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
+
+    fn supports_model(&self, _model: &str) -> bool {
+        // OpenAI is the catch-all provider for any model not claimed earlier.
+        true
+    }
+
+    fn generates_images(&self) -> bool {
+        true
+    }
+
+    async fn chat(&self, payload: &RequestPayload) -> ProviderResult<String> {
+        let response: ChatResponse = send(&self.client, Operation::Chat, |client| {
+            self.post(client, "/chat/completions").json(payload)
+        })
+        .await?
+        .json()
+        .await?;
+
+        let choice = response
+            .choices
+            .into_iter()
+            .next()
+            .ok_or_else(|| malformed(self.name(), "no choices returned"))?;
+
+        Ok(choice.message.content)
+    }
+
+    async fn chat_stream(&self, payload: &RequestPayload) -> ProviderResult<ChatStream> {
+        let mut body =
+            serde_json::to_value(payload).map_err(|e| AppError::Internal(e.to_string()))?;
+        body["stream"] = json!(true);
+        body["stream_options"] = json!({ "include_usage": true });
+
+        let response = send(&self.client, Operation::ChatStream, |client| {
+            self.post(client, "/chat/completions").json(&body)
+        })
+        .await?;
+
+        // The finish reason arrives before the trailing usage chunk, so it is
+        // held back and reported once the stream signals [DONE].
+        let events = sse::decode(response)
+            .scan(None, |stop_reason: &mut Option<String>, event| {
+                let events = match event {
+                    Ok(event) if event.data == "[DONE]" => vec![Ok(ChatEvent::Done {
+                        stop_reason: stop_reason.take(),
+                    })],
+                    Ok(event) => match serde_json::from_str::<ChatChunk>(&event.data) {
+                        Ok(chunk) => chunk_events(chunk, stop_reason),
+                        Err(e) => vec![Err(malformed("openai", e))],
+                    },
+                    Err(e) => vec![Err(e)],
+                };
+                future::ready(Some(events))
+            })
+            .flat_map(stream::iter)
+            .boxed();
+
+        Ok(events)
+    }
+
+    async fn image(&self, payload: &ImageRequestPayload) -> ProviderResult<String> {
+        let response: ImageResponse = send(&self.client, Operation::Image, |client| {
+            self.post(client, "/images/generations").json(payload)
+        })
+        .await?
+        .json()
+        .await?;
+
+        response
+            .data
+            .into_iter()
+            .next()
+            .and_then(|image| image.url)
+            .ok_or_else(|| malformed(self.name(), "no images returned"))
+    }
+
+    async fn image_data(&self, payload: &ImageRequestPayload) -> ProviderResult<Vec<u8>> {
+        // Hosted image URLs expire after an hour, so ask for the image itself.
+        let mut body =
+            serde_json::to_value(payload).map_err(|e| AppError::Internal(e.to_string()))?;
+        body["response_format"] = json!("b64_json");
+
+        let response: ImageResponse = send(&self.client, Operation::Image, |client| {
+            self.post(client, "/images/generations").json(&body)
+        })
+        .await?
+        .json()
+        .await?;
+
+        let encoded = response
+            .data
+            .into_iter()
+            .next()
+            .and_then(|image| image.b64_json)
+            .ok_or_else(|| malformed(self.name(), "no images returned"))?;
+
+        BASE64_STANDARD
+            .decode(encoded)
+            .map_err(|e| malformed(self.name(), e))
+    }
+
+    async fn speech(&self, payload: &TextToSpeechRequestPayload) -> ProviderResult<Vec<u8>> {
+        let audio = send(&self.client, Operation::Speech, |client| {
+            self.post(client, "/audio/speech").json(payload)
+        })
+        .await?
+        .bytes()
+        .await?;
+
+        Ok(audio.to_vec())
+    }
+
+    async fn transcription(
+        &self,
+        payload: &SpeechToTextRequestPayload,
+        file_contents: &[u8],
+    ) -> ProviderResult<String> {
+        // A multipart form can't be cloned, so each attempt builds its own.
+        let response: TranscriptionResponse =
+            send(&self.client, Operation::Transcription, |client| {
+                let mut headers = HeaderMap::new();
+                headers.insert(CONTENT_TYPE, HeaderValue::from_static("audio/wav"));
+
+                let part = reqwest::multipart::Part::bytes(file_contents.to_vec())
+                    .file_name(payload.file.clone())
+                    .headers(headers);
+
+                let form = reqwest::multipart::Form::new()
+                    .text("model", payload.model.clone())
+                    .part("file", part);
+
+                self.post(client, "/audio/transcriptions").multipart(form)
+            })
+            .await?
+            .json()
+            .await?;
+
+        Ok(response.text)
+    }
+
+    async fn embeddings(&self, payload: &EmbeddingRequestPayload) -> ProviderResult<Vec<Vec<f64>>> {
+        let response: EmbeddingResponse = send(&self.client, Operation::Embeddings, |client| {
+            self.post(client, "/embeddings").json(payload)
+        })
+        .await?
+        .json()
+        .await?;
+
+        Ok(response.data.into_iter().map(|d| d.embedding).collect())
+    }
+}
+
+fn chunk_events(
+    chunk: ChatChunk,
+    stop_reason: &mut Option<String>,
+) -> Vec<ProviderResult<ChatEvent>> {
+    let mut events = Vec::new();
+
+    for choice in chunk.choices {
+        if let Some(text) = choice.delta.content.filter(|text| !text.is_empty()) {
+            events.push(Ok(ChatEvent::Delta { text }));
+        }
+        if choice.finish_reason.is_some() {
+            *stop_reason = choice.finish_reason;
+        }
+    }
+
+    if let Some(usage) = chunk.usage {
+        events.push(Ok(ChatEvent::Usage {
+            input_tokens: Some(usage.prompt_tokens),
+            output_tokens: Some(usage.completion_tokens),
+        }));
+    }
+
+    events
+}
@@ -0,0 +1,165 @@
+// providers/sse.rs
+use super::ProviderResult;
+use futures::stream::{self, BoxStream};
+use futures::StreamExt;
+
+/// A single Server-Sent Event as received from an upstream provider. Both
+/// OpenAI and Anthropic repeat the event type inside the JSON payload, so
+/// only the data field is kept.
+#[derive(Debug)]
+pub(crate) struct SseEvent {
+    pub(crate) data: String,
+}
+
+/// Splits a streaming HTTP response body into Server-Sent Events.
+pub(crate) fn decode(response: reqwest::Response) -> BoxStream<'static, ProviderResult<SseEvent>> {
+    let body = response.bytes_stream().boxed();
+
+    stream::unfold(
+        (body, Vec::new(), false),
+        |(mut body, mut buffer, mut finished)| async move {
+            loop {
+                if let Some(event) = take_event(&mut buffer, finished) {
+                    return Some((Ok(event), (body, buffer, finished)));
+                }
+
+                if finished {
+                    return None;
+                }
+
+                match body.next().await {
+                    Some(Ok(chunk)) => append_chunk(&mut buffer, &chunk),
+                    Some(Err(e)) => return Some((Err(e.into()), (body, buffer, true))),
+                    None => finished = true,
+                }
+            }
+        },
+    )
+    .boxed()
+}
+
+// Line endings may be CRLF; dropping carriage returns lets event boundaries
+// be found with a plain blank-line search.
+fn append_chunk(buffer: &mut Vec<u8>, chunk: &[u8]) {
+    buffer.extend(chunk.iter().filter(|&&b| b != b'\r'));
+}
+
+fn take_event(buffer: &mut Vec<u8>, finished: bool) -> Option<SseEvent> {
+    loop {
+        let block: Vec<u8> = match buffer.windows(2).position(|w| w == b"\n\n") {
+            Some(end) => {
+                let block = buffer[..end].to_vec();
+                buffer.drain(..end + 2);
+                block
+            }
+            None if finished && !buffer.is_empty() => std::mem::take(buffer),
+            None => return None,
+        };
+
+        if let Some(event) = parse_block(&String::from_utf8_lossy(&block)) {
+            return Some(event);
+        }
+    }
+}
+
+fn parse_block(block: &str) -> Option<SseEvent> {
+    let data: Vec<&str> = block
+        .lines()
+        .filter_map(|line| line.strip_prefix("data:"))
+        .map(|value| value.strip_prefix(' ').unwrap_or(value))
+        .collect();
+
+    if data.is_empty() {
+        return None;
+    }
+
+    Some(SseEvent {
+        data: data.join("\n"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `chunks` through the decoder as if they arrived one by one and
+    /// returns the data of every event, including any left when the body ends.
+    fn decode_chunks(chunks: &[&str]) -> Vec<String> {
+        let mut buffer = Vec::new();
+        let mut events = Vec::new();
+        for chunk in chunks {
+            append_chunk(&mut buffer, chunk.as_bytes());
+            while let Some(event) = take_event(&mut buffer, false) {
+                events.push(event.data);
+            }
+        }
+        while let Some(event) = take_event(&mut buffer, true) {
+            events.push(event.data);
+        }
+        events
+    }
+
+    #[test]
+    fn decodes_whole_events() {
+        assert_eq!(
+            decode_chunks(&["data: one\n\ndata: two\n\n"]),
+            ["one", "two"]
+        );
+    }
+
+    #[test]
+    fn joins_events_split_across_chunks() {
+        assert_eq!(
+            decode_chunks(&["da", "ta: {\"text\":", "\"hi\"}\n", "\ndata: two\n\n"]),
+            ["{\"text\":\"hi\"}", "two"]
+        );
+    }
+
+    #[test]
+    fn joins_multi_line_data_fields() {
+        assert_eq!(
+            decode_chunks(&["data: first\ndata:second\ndata: third\n\n"]),
+            ["first\nsecond\nthird"]
+        );
+    }
+
+    #[test]
+    fn accepts_crlf_line_endings() {
+        assert_eq!(
+            decode_chunks(&["data: one\r\n\r", "\ndata: two\r\n\r\n"]),
+            ["one", "two"]
+        );
+    }
+
+    #[test]
+    fn skips_blocks_without_data() {
+        assert_eq!(
+            decode_chunks(&[": keep-alive\n\nevent: ping\n\ndata: one\n\n"]),
+            ["one"]
+        );
+    }
+
+    #[test]
+    fn keeps_other_fields_out_of_the_data() {
+        assert_eq!(
+            decode_chunks(&["event: content_block_delta\nid: 7\ndata: {}\n\n"]),
+            ["{}"]
+        );
+    }
+
+    #[test]
+    fn passes_done_through_as_data() {
+        assert_eq!(
+            decode_chunks(&["data: {}\n\ndata: [DONE]\n\n"]),
+            ["{}", "[DONE]"]
+        );
+    }
+
+    #[test]
+    fn flushes_an_unterminated_event_when_the_body_ends() {
+        assert_eq!(
+            decode_chunks(&["data: one\n\ndata: [DONE]"]),
+            ["one", "[DONE]"]
+        );
+    }
+}
@@ -1,69 +1,572 @@
 // user_handlers.rs
-use actix_web::{web, HttpResponse, Responder};
-use rusqlite::{params, Connection};
+use crate::ai_handlers::{provider_for, ImageRequestPayload};
+use crate::auth::{self, AuthenticatedUser, JwtKeys};
+use crate::config::Config;
+use crate::db::Database;
+use crate::error::{AppError, FieldError};
+use crate::game_events::{GameEvent, GameEvents};
+use crate::game_handlers::spawn_round_image;
+use crate::game_store::{self, update_game};
+use crate::providers::ProviderRegistry;
+use crate::usernames::{self, UsernamePolicy};
+use actix_web::{web, HttpResponse};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+const MIN_PASSWORD_LEN: usize = 8;
+const MAX_PASSWORD_LEN: usize = 128;
+const MAX_AVATAR_PROMPT_LEN: usize = 400;
+
+// What deleted users are shown as in the games they played.
+pub(crate) const DELETED_USERNAME: &str = "Deleted player";
+
+const GUEST_ADJECTIVES: &[&str] = &[
+    "Brave", "Clever", "Curious", "Gentle", "Jolly", "Lucky", "Mighty", "Quiet", "Swift", "Witty",
+];
+const GUEST_ANIMALS: &[&str] = &[
+    "Badger", "Falcon", "Koala", "Lynx", "Otter", "Panda", "Puffin", "Tiger", "Walrus", "Yak",
+];
+
 #[derive(Deserialize)]
 pub struct CreateUserRequest {
     username: String,
+    #[serde(default)]
+    password: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct LoginRequest {
+    username: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+pub struct ClaimUserRequest {
+    username: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateUserRequest {
+    username: String,
 }
 
+#[derive(Deserialize)]
+pub struct GenerateAvatarRequest {
+    prompt: String,
+}
+
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    refresh_token: String,
+}
+
+/// Returned whenever a user signs in: a short-lived access token for the
+/// `Authorization` header and a refresh token to renew it with.
 #[derive(Serialize)]
-pub struct CreateUserResponse {
-    user_id: String,
+pub struct SessionResponse {
+    pub(crate) user_id: String,
     token: String,
+    refresh_token: String,
 }
 
-pub async fn create_user(user_data: web::Json<CreateUserRequest>) -> impl Responder {
+#[derive(Serialize)]
+pub struct UserResponse {
+    user_id: String,
+    username: String,
+    is_guest: bool,
+    avatar: Option<String>,
+}
+
+pub async fn create_user(
+    db: web::Data<Database>,
+    keys: web::Data<JwtKeys>,
+    policy: web::Data<UsernamePolicy>,
+    user_data: web::Json<CreateUserRequest>,
+) -> Result<HttpResponse, AppError> {
+    let user_data = user_data.into_inner();
     let user_id = Uuid::new_v4().to_string();
-    let token = generate_jwt_token(&user_id);
 
-    let conn = match Connection::open("game_database.db") {
-        Ok(conn) => conn,
-        Err(e) => {
-            eprintln!("Error connecting to database: {}", e);
-            return HttpResponse::InternalServerError().finish();
-        }
-    };
+    // Passwords are optional; without one the account lives only as long as
+    // the client keeps its refresh token.
+    let password = user_data
+        .password
+        .map(|password| validate_password(&password).map(|_| password))
+        .transpose();
+    let (username, password) = validate_both(policy.validate(&user_data.username), password)?;
 
-    // Check if username already exists
-    let mut stmt = match conn.prepare("SELECT 1 FROM users WHERE username = ?1") {
-        Ok(stmt) => stmt,
-        Err(e) => {
-            eprintln!("Error preparing statement: {}", e);
-            return HttpResponse::InternalServerError().finish();
-        }
+    let password_hash = match password {
+        Some(password) => Some(auth::hash_password(password).await?),
+        None => None,
     };
 
-    let username_exists = match stmt.exists(params![&user_data.username]) {
-        Ok(exists) => exists,
-        Err(e) => {
-            eprintln!("Error checking for existing username: {}", e);
-            return HttpResponse::InternalServerError().finish();
+    let session = db
+        .run(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT INTO users (id, username) VALUES (?1, ?2)",
+                params![user_id, username],
+            )
+            .map_err(username_conflict)?;
+
+            if let Some(password_hash) = password_hash {
+                tx.execute(
+                    "INSERT INTO user_credentials (user_id, password_hash) VALUES (?1, ?2)",
+                    params![user_id, password_hash],
+                )?;
+            }
+
+            let session = start_session(&tx, &keys, user_id)?;
+            tx.commit()?;
+
+            Ok(session)
+        })
+        .await?;
+
+    Ok(HttpResponse::Ok().json(session))
+}
+
+pub async fn login(
+    db: web::Data<Database>,
+    keys: web::Data<JwtKeys>,
+    login_data: web::Json<LoginRequest>,
+) -> Result<HttpResponse, AppError> {
+    let login_data = login_data.into_inner();
+    let username = usernames::normalize(&login_data.username);
+    let invalid = || AppError::Unauthorized("Invalid username or password".to_string());
+
+    let credentials = db
+        .run(move |conn| {
+            conn.query_row(
+                "SELECT users.id, user_credentials.password_hash FROM users
+                 JOIN user_credentials ON user_credentials.user_id = users.id
+                 WHERE users.username = ?1 COLLATE NOCASE",
+                params![username],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+            )
+            .optional()
+            .map_err(AppError::from)
+        })
+        .await?;
+    let (user_id, password_hash) = credentials.ok_or_else(invalid)?;
+
+    if !auth::verify_password(login_data.password, password_hash).await? {
+        return Err(invalid());
+    }
+
+    let session = db
+        .run(move |conn| start_session(conn, &keys, user_id))
+        .await?;
+
+    Ok(HttpResponse::Ok().json(session))
+}
+
+/// Creates a guest account with a generated display name and signs it in.
+/// Guests can pick a username and password later with `/users/claim`.
+pub(crate) fn create_guest(conn: &Connection, keys: &JwtKeys) -> Result<SessionResponse, AppError> {
+    let user_id = Uuid::new_v4().to_string();
+    let mut username;
+
+    loop {
+        username = generate_guest_name();
+
+        let mut stmt = conn.prepare("SELECT 1 FROM users WHERE username = ?1 COLLATE NOCASE")?;
+        if !stmt.exists(params![username])? {
+            break;
         }
-    };
+    }
+
+    conn.execute(
+        "INSERT INTO users (id, username, is_guest) VALUES (?1, ?2, 1)",
+        params![user_id, username],
+    )?;
+
+    start_session(conn, keys, user_id)
+}
+
+/// Turns the caller's guest account into a registered one. The user id stays
+/// the same, so their games and scores carry over under the new name.
+pub async fn claim_user(
+    db: web::Data<Database>,
+    policy: web::Data<UsernamePolicy>,
+    user: AuthenticatedUser,
+    claim_data: web::Json<ClaimUserRequest>,
+) -> Result<HttpResponse, AppError> {
+    let claim_data = claim_data.into_inner();
+    let (username, _) = validate_both(
+        policy.validate(&claim_data.username),
+        validate_password(&claim_data.password),
+    )?;
+    let password_hash = auth::hash_password(claim_data.password).await?;
+
+    let profile = db
+        .run(move |conn| {
+            // Checking and claiming under one write lock keeps two claims
+            // of the same guest from both getting past the check.
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let is_guest: bool = tx
+                .query_row(
+                    "SELECT is_guest FROM users WHERE id = ?1 AND deleted_at IS NULL",
+                    params![user.user_id],
+                    |row| row.get(0),
+                )
+                .optional()?
+                .ok_or_else(|| AppError::Unauthorized("Unknown user".to_string()))?;
+
+            if !is_guest {
+                return Err(AppError::Conflict(
+                    "This account has already been claimed".to_string(),
+                ));
+            }
+
+            tx.execute(
+                "UPDATE users SET username = ?1, is_guest = 0 WHERE id = ?2",
+                params![username, user.user_id],
+            )
+            .map_err(username_conflict)?;
+            tx.execute(
+                "INSERT INTO user_credentials (user_id, password_hash) VALUES (?1, ?2)",
+                params![user.user_id, password_hash],
+            )?;
+            tx.commit()?;
+
+            load_user(conn, &user.user_id)
+        })
+        .await?;
+
+    Ok(HttpResponse::Ok().json(profile))
+}
+
+pub async fn get_user(
+    db: web::Data<Database>,
+    user_id: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let user_id = user_id.into_inner();
+    let profile = db.run(move |conn| load_user(conn, &user_id)).await?;
+
+    Ok(HttpResponse::Ok().json(profile))
+}
+
+/// Changes the caller's username, which is also what they're shown as in
+/// every game they've been in.
+pub async fn update_user(
+    db: web::Data<Database>,
+    policy: web::Data<UsernamePolicy>,
+    user: AuthenticatedUser,
+    user_data: web::Json<UpdateUserRequest>,
+) -> Result<HttpResponse, AppError> {
+    let username = policy.validate(&user_data.username)?;
+
+    let profile = db
+        .run(move |conn| {
+            let updated = conn
+                .execute(
+                    "UPDATE users SET username = ?1 WHERE id = ?2 AND deleted_at IS NULL",
+                    params![username, user.user_id],
+                )
+                .map_err(username_conflict)?;
+
+            if updated == 0 {
+                return Err(AppError::Unauthorized("Unknown user".to_string()));
+            }
+
+            load_user(conn, &user.user_id)
+        })
+        .await?;
+
+    Ok(HttpResponse::Ok().json(profile))
+}
+
+/// Deletes the caller's account and signs out all their sessions. They leave
+/// any game still in play; games they played keep their scores, but show them
+/// as a deleted player.
+pub async fn delete_user(
+    db: web::Data<Database>,
+    registry: web::Data<ProviderRegistry>,
+    events: web::Data<GameEvents>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let publisher = events.clone();
+
+    let drawn = db
+        .run(move |conn| {
+            let tx = conn.transaction()?;
+
+            // Leave every game still in play, so nobody waits on a player
+            // who is gone for good.
+            let mut departures = Vec::new();
+            for game_uuid in game_store::unfinished_games(&tx, &user.user_id)? {
+                departures.push(update_game(&tx, &game_uuid, |game_state| {
+                    Ok(game_state.remove_player(&user.user_id))
+                })?);
+            }
+
+            tx.execute(
+                "DELETE FROM refresh_tokens WHERE user_id = ?1",
+                params![user.user_id],
+            )?;
+            tx.execute(
+                "DELETE FROM user_credentials WHERE user_id = ?1",
+                params![user.user_id],
+            )?;
+            tx.execute(
+                "DELETE FROM avatars WHERE user_id = ?1",
+                params![user.user_id],
+            )?;
+            // The row stays behind for the games that refer to it, with the name
+            // freed up for someone else.
+            let deleted = tx.execute(
+                "UPDATE users SET username = id, avatar = NULL, deleted_at = ?1
+                 WHERE id = ?2 AND deleted_at IS NULL",
+                params![auth::unix_now().as_secs(), user.user_id],
+            )?;
+
+            if deleted == 0 {
+                return Err(AppError::Unauthorized("Unknown user".to_string()));
+            }
 
-    if username_exists {
-        return HttpResponse::BadRequest().body("Username already exists");
+            tx.commit()?;
+
+            let mut drawn = Vec::new();
+            for (before, game_state, drawn_prompt) in departures {
+                publisher.publish(
+                    before,
+                    &game_state,
+                    Some(GameEvent::PlayerLeft {
+                        player_id: user.user_id.clone(),
+                    }),
+                );
+                if let Some(prompt) = drawn_prompt {
+                    drawn.push((game_state, prompt));
+                }
+            }
+
+            Ok(drawn)
+        })
+        .await?;
+
+    for (game_state, prompt) in drawn {
+        spawn_round_image(
+            db.clone(),
+            registry.clone(),
+            events.clone(),
+            &game_state,
+            prompt,
+        );
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Generates an avatar for the caller from a short description and saves it
+/// to their profile. The image itself is stored and served by
+/// [`get_avatar`].
+pub async fn generate_avatar(
+    db: web::Data<Database>,
+    registry: web::Data<ProviderRegistry>,
+    config: web::Data<Config>,
+    user: AuthenticatedUser,
+    avatar_data: web::Json<GenerateAvatarRequest>,
+) -> Result<HttpResponse, AppError> {
+    let description = avatar_data.prompt.trim();
+
+    if description.is_empty() {
+        return Err(AppError::BadRequest(
+            "Avatar prompt cannot be empty".to_string(),
+        ));
+    }
+    if description.chars().count() > MAX_AVATAR_PROMPT_LEN {
+        return Err(AppError::BadRequest(format!(
+            "Avatar prompt must be at most {} characters",
+            MAX_AVATAR_PROMPT_LEN
+        )));
     }
 
-    match conn.execute(
-        "INSERT INTO users (id, username) VALUES (?1, ?2)",
-        params![user_id, user_data.username],
-    ) {
-        Ok(_) => (),
-        Err(e) => {
-            eprintln!("Error inserting user: {}", e);
-            return HttpResponse::InternalServerError().finish();
+    let payload = ImageRequestPayload {
+        model: config.models.image.clone(),
+        prompt: format!("A square profile picture avatar of {}", description),
+        size: "1024x1024".to_string(),
+        quality: "standard".to_string(),
+        n: 1,
+    };
+    let image = provider_for(&registry, &payload.model)?
+        .image_data(&payload)
+        .await?;
+
+    let profile = db
+        .run(move |conn| {
+            let now = auth::unix_now().as_secs();
+            // Versioned so clients don't keep showing a cached older avatar.
+            let avatar = format!("/users/{}/avatar?v={}", user.user_id, now);
+
+            let tx = conn.transaction()?;
+            let updated = tx.execute(
+                "UPDATE users SET avatar = ?1 WHERE id = ?2 AND deleted_at IS NULL",
+                params![avatar, user.user_id],
+            )?;
+
+            if updated == 0 {
+                return Err(AppError::Unauthorized("Unknown user".to_string()));
+            }
+
+            tx.execute(
+                "INSERT OR REPLACE INTO avatars (user_id, image, content_type, updated_at)
+                 VALUES (?1, ?2, 'image/png', ?3)",
+                params![user.user_id, image, now],
+            )?;
+            tx.commit()?;
+
+            load_user(conn, &user.user_id)
+        })
+        .await?;
+
+    Ok(HttpResponse::Ok().json(profile))
+}
+
+pub async fn get_avatar(
+    db: web::Data<Database>,
+    user_id: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let user_id = user_id.into_inner();
+    let (image, content_type) = db
+        .run(move |conn| {
+            conn.query_row(
+                "SELECT avatars.image, avatars.content_type FROM avatars
+                 JOIN users ON users.id = avatars.user_id
+                 WHERE avatars.user_id = ?1 AND users.deleted_at IS NULL",
+                params![user_id],
+                |row| Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, String>(1)?)),
+            )
+            .optional()?
+            .ok_or_else(|| AppError::NotFound("Avatar not found".to_string()))
+        })
+        .await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header(("Cache-Control", "public, max-age=86400"))
+        .body(image))
+}
+
+/// Trades a refresh token for a new access token and a new refresh token.
+/// The old refresh token stops working.
+pub async fn refresh(
+    db: web::Data<Database>,
+    keys: web::Data<JwtKeys>,
+    refresh_data: web::Json<RefreshRequest>,
+) -> Result<HttpResponse, AppError> {
+    let presented = refresh_data.into_inner().refresh_token;
+    let (user_id, refresh_token) = db
+        .run({
+            let keys = keys.clone();
+            move |conn| auth::rotate_refresh_token(conn, &keys, &presented)
+        })
+        .await?;
+    let token = keys.issue(&user_id)?;
+
+    Ok(HttpResponse::Ok().json(SessionResponse {
+        user_id,
+        token,
+        refresh_token,
+    }))
+}
+
+/// Revokes the refresh token and every token rotated from it. Access tokens
+/// already issued stay valid until they expire.
+pub async fn logout(
+    db: web::Data<Database>,
+    refresh_data: web::Json<RefreshRequest>,
+) -> Result<HttpResponse, AppError> {
+    let presented = refresh_data.into_inner().refresh_token;
+    db.run(move |conn| auth::revoke_refresh_token(conn, &presented))
+        .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+fn start_session(
+    conn: &Connection,
+    keys: &JwtKeys,
+    user_id: String,
+) -> Result<SessionResponse, AppError> {
+    let token = keys.issue(&user_id)?;
+    let refresh_token = auth::issue_refresh_token(conn, keys, &user_id, None)?;
+
+    Ok(SessionResponse {
+        user_id,
+        token,
+        refresh_token,
+    })
+}
+
+fn load_user(conn: &Connection, user_id: &str) -> Result<UserResponse, AppError> {
+    conn.query_row(
+        "SELECT id, username, is_guest, avatar FROM users WHERE id = ?1 AND deleted_at IS NULL",
+        params![user_id],
+        |row| {
+            Ok(UserResponse {
+                user_id: row.get(0)?,
+                username: row.get(1)?,
+                is_guest: row.get(2)?,
+                avatar: row.get(3)?,
+            })
+        },
+    )
+    .optional()?
+    .ok_or_else(|| AppError::NotFound("User not found".to_string()))
+}
+
+/// Maps a violation of the UNIQUE constraint on `users.username` to a 409.
+fn username_conflict(e: rusqlite::Error) -> AppError {
+    match e {
+        rusqlite::Error::SqliteFailure(ref failure, _)
+            if failure.code == rusqlite::ErrorCode::ConstraintViolation =>
+        {
+            AppError::Conflict("Username already exists".to_string())
         }
+        e => e.into(),
+    }
+}
+
+fn generate_guest_name() -> String {
+    let mut rng = rand::thread_rng();
+
+    format!(
+        "{}{}{}",
+        GUEST_ADJECTIVES.choose(&mut rng).unwrap(),
+        GUEST_ANIMALS.choose(&mut rng).unwrap(),
+        rng.gen_range(10..100)
+    )
+}
+
+fn validate_password(password: &str) -> Result<(), AppError> {
+    let length = password.chars().count();
+    if !(MIN_PASSWORD_LEN..=MAX_PASSWORD_LEN).contains(&length) {
+        return Err(AppError::Validation(vec![FieldError::new(
+            "password",
+            "invalid_length",
+            format!(
+                "Password must be between {} and {} characters",
+                MIN_PASSWORD_LEN, MAX_PASSWORD_LEN
+            ),
+        )]));
     }
 
-    HttpResponse::Ok().json(CreateUserResponse { user_id, token })
+    Ok(())
 }
 
-fn generate_jwt_token(user_id: &str) -> String {
-    // TODO: Implement JWT token generation logic
-    // For now, you can return a dummy token
-    format!("dummy_token_{}", user_id)
-}
\ No newline at end of file
+/// Combines two validation results, so a client with several bad fields
+/// hears about all of them at once.
+fn validate_both<A, B>(a: Result<A, AppError>, b: Result<B, AppError>) -> Result<(A, B), AppError> {
+    match (a, b) {
+        (Ok(a), Ok(b)) => Ok((a, b)),
+        (Err(AppError::Validation(mut a)), Err(AppError::Validation(b))) => {
+            a.extend(b);
+            Err(AppError::Validation(a))
+        }
+        (Err(e), _) | (_, Err(e)) => Err(e),
+    }
+}
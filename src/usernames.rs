@@ -0,0 +1,277 @@
+// usernames.rs
+use crate::config::UsernameConfig;
+use crate::error::{AppError, FieldError};
+use std::fs;
+use unicode_normalization::UnicodeNormalization;
+
+const MIN_USERNAME_LEN: usize = 3;
+const MAX_USERNAME_LEN: usize = 20;
+
+/// Names that could be mistaken for the service, staff or a route.
+const RESERVED_USERNAMES: &[&str] = &[
+    "admin",
+    "administrator",
+    "anonymous",
+    "deleted",
+    "guest",
+    "me",
+    "mod",
+    "moderator",
+    "null",
+    "root",
+    "staff",
+    "support",
+    "system",
+    "undefined",
+];
+
+// Matched anywhere in a name, so kept to words that rarely appear inside
+// innocent ones.
+const DEFAULT_BLOCKLIST: &[&str] = &[
+    "asshole", "bitch", "cunt", "fuck", "nigg", "shit", "slut", "whore",
+];
+
+/// Decides which usernames are acceptable. Uniqueness is left to the
+/// database, which compares names case-insensitively.
+pub struct UsernamePolicy {
+    blocklist: Vec<String>,
+}
+
+impl UsernamePolicy {
+    pub fn new(blocklist: Vec<String>) -> Self {
+        let blocklist = blocklist
+            .iter()
+            .map(|word| fold(word))
+            .filter(|word| !word.is_empty())
+            .collect();
+
+        UsernamePolicy { blocklist }
+    }
+
+    /// Uses the built-in blocklist plus the configured words and the words
+    /// in the blocklist file (one per line).
+    pub fn from_config(config: &UsernameConfig) -> Result<Self, String> {
+        let mut blocklist: Vec<String> = DEFAULT_BLOCKLIST.iter().map(|w| w.to_string()).collect();
+        blocklist.extend(config.blocklist.iter().cloned());
+
+        if let Some(path) = &config.blocklist_file {
+            let words = fs::read_to_string(path)
+                .map_err(|e| format!("Could not read username blocklist file {}: {}", path, e))?;
+            blocklist.extend(words.lines().map(str::to_string));
+        }
+
+        Ok(Self::new(blocklist))
+    }
+
+    /// Returns the username in the form it should be stored in, or the
+    /// reasons it was rejected.
+    pub fn validate(&self, username: &str) -> Result<String, AppError> {
+        let username = normalize(username);
+        let mut errors = Vec::new();
+
+        let length = username.chars().count();
+        if length < MIN_USERNAME_LEN {
+            errors.push(FieldError::new(
+                "username",
+                "too_short",
+                format!("Username must be at least {} characters", MIN_USERNAME_LEN),
+            ));
+        } else if length > MAX_USERNAME_LEN {
+            errors.push(FieldError::new(
+                "username",
+                "too_long",
+                format!("Username must be at most {} characters", MAX_USERNAME_LEN),
+            ));
+        }
+
+        if !username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            errors.push(FieldError::new(
+                "username",
+                "invalid_characters",
+                "Username may only contain letters, digits, '_' and '-'".to_string(),
+            ));
+        } else if username.starts_with(['_', '-']) {
+            errors.push(FieldError::new(
+                "username",
+                "invalid_start",
+                "Username must start with a letter or digit".to_string(),
+            ));
+        }
+
+        if RESERVED_USERNAMES
+            .iter()
+            .any(|reserved| username.eq_ignore_ascii_case(reserved))
+        {
+            errors.push(FieldError::new(
+                "username",
+                "reserved",
+                "That username is reserved".to_string(),
+            ));
+        } else if self.is_blocked(&username) {
+            errors.push(FieldError::new(
+                "username",
+                "not_allowed",
+                "That username is not allowed".to_string(),
+            ));
+        }
+
+        if errors.is_empty() {
+            Ok(username)
+        } else {
+            Err(AppError::Validation(errors))
+        }
+    }
+
+    fn is_blocked(&self, username: &str) -> bool {
+        let folded = fold(username);
+        self.blocklist.iter().any(|word| folded.contains(word))
+    }
+}
+
+/// NFKC-normalizes a username so that look-alike forms (full-width letters,
+/// ligatures and the like) map onto the same plain characters.
+pub fn normalize(username: &str) -> String {
+    username.nfkc().collect::<String>().trim().to_string()
+}
+
+/// Reduces a name to lowercase letters, undoing common digit-for-letter
+/// swaps, so blocklisted words can't be dodged with `sh1t` or `f_u_c_k`.
+fn fold(name: &str) -> String {
+    normalize(name)
+        .chars()
+        .filter_map(|c| match c.to_ascii_lowercase() {
+            '0' => Some('o'),
+            '1' => Some('i'),
+            '3' => Some('e'),
+            '4' => Some('a'),
+            '5' => Some('s'),
+            '7' => Some('t'),
+            c if c.is_alphanumeric() => Some(c),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> UsernamePolicy {
+        UsernamePolicy::from_config(&UsernameConfig {
+            blocklist: vec!["Badword".to_string()],
+            blocklist_file: None,
+        })
+        .unwrap()
+    }
+
+    /// The stored name, or the codes of every reason it was rejected.
+    fn check(username: &str) -> Result<String, Vec<&'static str>> {
+        policy().validate(username).map_err(|e| match e {
+            AppError::Validation(errors) => errors.iter().map(|e| e.code).collect(),
+            e => panic!("unexpected error: {}", e),
+        })
+    }
+
+    #[test]
+    fn accepts_and_normalizes_valid_names() {
+        let cases = [
+            ("alice", "alice"),
+            ("Alice_42", "Alice_42"),
+            ("  bob-smith  ", "bob-smith"),
+            ("ｊｏｈｎｎｙ", "johnny"),
+            ("ﬁdo", "fido"),
+            ("x1y", "x1y"),
+            ("a2345678901234567890", "a2345678901234567890"),
+        ];
+
+        for (input, stored) in cases {
+            assert_eq!(check(input), Ok(stored.to_string()), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_lengths_and_characters() {
+        let cases: &[(&str, &[&str])] = &[
+            ("ab", &["too_short"]),
+            ("   ", &["too_short"]),
+            ("a23456789012345678901", &["too_long"]),
+            ("bob smith", &["invalid_characters"]),
+            ("bob.smith", &["invalid_characters"]),
+            ("josé", &["invalid_characters"]),
+            ("_bob", &["invalid_start"]),
+            ("-bob", &["invalid_start"]),
+            ("a!", &["too_short", "invalid_characters"]),
+        ];
+
+        for (input, codes) in cases {
+            assert_eq!(check(input), Err(codes.to_vec()), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn rejects_reserved_names_in_any_case_or_width() {
+        for input in [
+            "admin",
+            "ADMIN",
+            "Admin",
+            "ａｄｍｉｎ",
+            "ＳＹＳＴＥＭ",
+            " root ",
+        ] {
+            assert_eq!(check(input), Err(vec!["reserved"]), "{:?}", input);
+        }
+
+        // Only exact matches are reserved.
+        assert!(check("admin2").is_ok());
+        assert!(check("the_admin").is_ok());
+    }
+
+    #[test]
+    fn rejects_blocklisted_words_through_disguises() {
+        let cases = [
+            "shit",
+            "SHIT",
+            "bullshit99",
+            "sh1t",
+            "5h1t",
+            "s_h_i_t",
+            "s-h-i-t",
+            "ｓｈｉｔ",
+            "f_u_c_k",
+            "badword",
+            "BADW0RD",
+            "b4dw0rd_fan",
+        ];
+
+        for input in cases {
+            assert_eq!(check(input), Err(vec!["not_allowed"]), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn blocklist_words_are_folded_too() {
+        let policy = UsernamePolicy::new(vec!["Ｎ00b".to_string(), "  ".to_string()]);
+        assert!(policy.is_blocked("noob_master"));
+        assert!(policy.is_blocked("N0OB"));
+        assert!(!policy.is_blocked("nob"));
+    }
+
+    #[test]
+    fn fold_undoes_case_separators_and_leetspeak() {
+        let cases = [
+            ("Hello", "hello"),
+            ("h_e-l l.o", "hello"),
+            ("h3ll0", "hello"),
+            ("1337", "ieet"),
+            ("4s5t7", "asstt"),
+            ("ｈｅｌｌｏ", "hello"),
+        ];
+
+        for (input, folded) in cases {
+            assert_eq!(fold(input), folded, "{:?}", input);
+        }
+    }
+}